@@ -0,0 +1,168 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::discord::SenderNormalization;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// Extra context for senders, loaded from a TOML lookup file
+///
+/// Each table in the file is keyed by sender address and holds the fields added to that
+/// sender's messages. Addresses are normalized before matching, for example:
+///
+/// ```toml
+/// ["alerts@example.com"]
+/// owner = "Jade"
+/// team = "Infrastructure"
+/// ```
+pub struct AnnotationLookup {
+    /// Path to the lookup file
+    path: PathBuf,
+    /// Whether the file is read again when it changes
+    reload: bool,
+    /// How addresses are normalized before matching
+    normalization: SenderNormalization,
+    /// Parsed lookup, and when the file was last modified
+    state: RwLock<(Annotations, Option<SystemTime>)>,
+}
+
+/// Fields to add for each normalized, lowercased sender address
+type Annotations = HashMap<String, Vec<(String, String)>>;
+
+impl AnnotationLookup {
+    /// Loads the lookup file
+    ///
+    /// # Parameters
+    /// * `path` - path to the lookup file
+    /// * `reload` - whether to read the file again when it changes
+    /// * `normalization` - how addresses are normalized before matching
+    pub fn load(
+        path: &Path,
+        reload: bool,
+        normalization: SenderNormalization,
+    ) -> Result<Self, AnnotationError> {
+        let modified = modified(path);
+        let annotations = parse(path, &normalization)?;
+        Ok(Self {
+            path: path.into(),
+            reload,
+            normalization,
+            state: RwLock::new((annotations, modified)),
+        })
+    }
+
+    /// Returns the fields to add for a sender, or an empty list if it isn't in the lookup
+    ///
+    /// # Parameters
+    /// * `address` - the sender's bare address
+    pub fn annotations(&self, address: &str) -> Vec<(String, String)> {
+        if self.reload {
+            self.reload_if_changed();
+        }
+        match self.state.read() {
+            Ok(state) => state
+                .0
+                .get(&lookup_key(&self.normalization, address))
+                .cloned()
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Reads the lookup file again if it was modified since it was last read
+    ///
+    /// A file that fails to parse is logged and the previous lookup kept.
+    fn reload_if_changed(&self) {
+        let modified = modified(&self.path);
+        let changed = match self.state.read() {
+            Ok(state) => state.1 != modified,
+            Err(_) => false,
+        };
+        if !changed {
+            return;
+        }
+        match parse(&self.path, &self.normalization) {
+            Ok(annotations) => {
+                info!("Reloaded annotations from {}", self.path.display());
+                if let Ok(mut state) = self.state.write() {
+                    *state = (annotations, modified);
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to reload annotations from {}: {:?}",
+                    self.path.display(),
+                    e
+                );
+                // Don't retry until the file changes again
+                if let Ok(mut state) = self.state.write() {
+                    state.1 = modified;
+                }
+            }
+        }
+    }
+}
+
+/// Returns when a file was last modified, if that can be found out
+///
+/// # Parameters
+/// * `path` - path to the file
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Returns the key an address is looked up by, matching case insensitively
+///
+/// # Parameters
+/// * `normalization` - how the address is normalized
+/// * `address` - the sender's bare address
+fn lookup_key(normalization: &SenderNormalization, address: &str) -> String {
+    normalization.normalize(address).to_ascii_lowercase()
+}
+
+/// Parses a lookup file
+///
+/// # Parameters
+/// * `path` - path to the lookup file
+/// * `normalization` - how the addresses are normalized
+fn parse(path: &Path, normalization: &SenderNormalization) -> Result<Annotations, AnnotationError> {
+    let contents = fs::read(path).map_err(AnnotationError::Io)?;
+    let entries: BTreeMap<String, BTreeMap<String, String>> =
+        toml::from_slice(&contents).map_err(AnnotationError::Parse)?;
+    Ok(entries
+        .into_iter()
+        .map(|(address, fields)| {
+            (
+                lookup_key(normalization, &address),
+                fields.into_iter().collect(),
+            )
+        })
+        .collect())
+}
+
+/// Errors from loading an annotation lookup file
+#[derive(Debug)]
+pub enum AnnotationError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The file isn't a table of tables of strings
+    Parse(toml::de::Error),
+}
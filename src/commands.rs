@@ -0,0 +1,70 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use samotop::model::command::SmtpPath;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Action taken when mail is sent to a command recipient
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandAction {
+    /// Posts a message showing the bridge is up
+    Ping,
+    /// Toggles turning away all mail with a temporary failure
+    Mute,
+    /// Posts how many messages have been sent and how many failed
+    Stats,
+}
+
+/// Recipient addresses that control the bridge instead of receiving mail
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct CommandRecipients {
+    /// Actions keyed by recipient address
+    addresses: HashMap<String, CommandAction>,
+}
+
+impl CommandRecipients {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `addresses` - actions keyed by recipient address
+    pub fn new(addresses: HashMap<String, CommandAction>) -> Self {
+        Self { addresses }
+    }
+
+    /// Returns whether no command recipients are configured
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Returns the action for a recipient, if it is a command recipient
+    ///
+    /// # Parameters
+    /// * `rcpt` - the recipient path
+    pub fn action(&self, rcpt: &SmtpPath) -> Option<CommandAction> {
+        if self.is_empty() {
+            return None;
+        }
+        let rcpt = rcpt.to_string();
+        let address = rcpt.trim_start_matches('<').trim_end_matches('>');
+        self.addresses
+            .iter()
+            .find(|(command, _)| command.eq_ignore_ascii_case(address))
+            .map(|(_, action)| *action)
+    }
+}
@@ -14,70 +14,1343 @@
 // You should have received a copy of the GNU General Public License
 // along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::discord::{DiscordWebhookAuth, DiscordWebhookAuthUrlError};
+use crate::annotate::{AnnotationError, AnnotationLookup};
+use crate::commands::CommandRecipients;
+use crate::discord::{
+    DiscordWebhookAuth, DiscordWebhookAuthUrlError, SenderNormalization, REDACTED,
+};
+use crate::handler::{
+    self, AuthorLink, AuthorLinkError, BccHandling, BodyLayout, EmbedHandler, FieldSpec,
+    SectionSplit, SubjectFallback, SubjectPlacement, WebhookUsername,
+};
+use crate::route::{RouteDestination, Routes, RoutingDefault};
+use crate::smtp::{tls_config_none, AllowedSenders, LineEndings, ServedDomains, TlsSetting};
+use crate::spam::SpamCheck;
+use crate::throttle::{DEFAULT_ERROR_LOG_EVERY, DEFAULT_ERROR_LOG_INTERVAL};
+use crate::{
+    DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_EMBED_CHARS, DEFAULT_MAX_SPLIT_MESSAGES,
+    DEFAULT_RETRY_ATTEMPTS, DEFAULT_RETRY_BASE, DEFAULT_RETRY_MAX_TOTAL,
+};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE,
+};
+use samotop::model::controll::{TlsConfig, TlsIdFile};
 use serde::Deserialize;
-use std::net::{IpAddr, SocketAddr};
-use url::Url;
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Overall config file
 #[derive(Debug, Deserialize)]
 pub struct Config {
     /// SMTP section. Used to configure the SMTP server
+    #[serde(default)]
     pub smtp: SmtpConfig,
     /// Discord section. Used to configure the Discord webhook
     pub discord: DiscordConfig,
+    /// Filter section. Used to pipe mail through an external command
+    pub filter: Option<FilterConfig>,
+    /// Format section. Used to configure how mail is laid out in Discord
+    #[serde(default)]
+    pub format: FormatConfig,
+    /// Spam section. Used to honor spam headers added by an upstream filter
+    pub spam: Option<SpamCheck>,
+    /// Format section as written, which a route's own format settings are laid over
+    #[serde(skip)]
+    format_table: toml::value::Table,
+}
+
+impl Config {
+    /// Reads and parses a config file, checking the Discord credentials
+    ///
+    /// # Parameters
+    /// * `path` - path to the config file
+    pub fn from_file(path: &Path) -> Result<Self, ConfigLoadError> {
+        let contents = fs::read(path).map_err(ConfigLoadError::Io)?;
+        let config = Self::from_slice(&contents).map_err(ConfigLoadError::Toml)?;
+        config.validate().map_err(ConfigLoadError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Parses a config, without checking it
+    ///
+    /// # Parameters
+    /// * `contents` - the config file contents
+    pub fn from_slice(contents: &[u8]) -> Result<Self, toml::de::Error> {
+        let mut config: Self = toml::from_slice(contents)?;
+        // Routes inherit every format setting they don't set, so the section is kept as written
+        let value: toml::Value = toml::from_slice(contents)?;
+        if let Some(toml::Value::Table(format)) = value.get("format") {
+            config.format_table = format.clone();
+        }
+        Ok(config)
+    }
+
+    /// Returns the recipient routes, each with its own handler if it sets its own format
+    pub fn routes(&self) -> Result<Routes<RouteDestination>, DiscordConfigError> {
+        self.discord
+            .routes(&self.format_table, &self.smtp.sender_normalization)
+    }
+
+    /// Checks settings that deserializing can't
+    ///
+    /// Bad credentials are caught here, rather than when the webhook is first fetched.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        use ConfigError::*;
+        let smtp = &self.smtp;
+        if smtp.listen.is_none() && smtp.listen_port == 0 {
+            return Err(ZeroPort("smtp.listen_port"));
+        }
+        if smtp.socket_addrs().iter().any(|addr| addr.port() == 0) {
+            return Err(ZeroPort("smtp.listen"));
+        }
+        if smtp.service_name.as_deref().map(str::trim) == Some("") {
+            return Err(EmptyServiceName("smtp.service_name"));
+        }
+        smtp.check_listen().map_err(|e| {
+            let field = match e {
+                SmtpConfigError::UnixListenUnsupported(_) => "smtp.listen_unix",
+                _ => "smtp.listen",
+            };
+            Smtp(field, e)
+        })?;
+        smtp.check_tls().map_err(|e| {
+            let field = match e {
+                SmtpConfigError::MissingTlsCertFile => "smtp.tls_cert_file",
+                _ => "smtp.tls_mode",
+            };
+            Smtp(field, e)
+        })?;
+        self.discord.get_auth().map_err(|e| Auth("discord", e))?;
+        self.routes().map_err(|e| Auth("discord.routes", e))?;
+        self.discord
+            .http_headers()
+            .map_err(|e| Auth("discord.http_headers", e))?;
+        Ok(())
+    }
+}
+
+/// Settings that are well formed but invalid, each with the offending field
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A listen port is zero, which would bind a random port
+    ZeroPort(&'static str),
+    /// The service name is empty
+    EmptyServiceName(&'static str),
+    /// The SMTP listen or TLS settings are unusable
+    Smtp(&'static str, SmtpConfigError),
+    /// The Discord credentials, routes or HTTP headers are missing or invalid
+    Auth(&'static str, DiscordConfigError),
+}
+
+/// Errors loading the config file
+#[derive(Debug)]
+pub enum ConfigLoadError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The file isn't a valid config
+    Toml(toml::de::Error),
+    /// The config is well formed but invalid
+    Invalid(ConfigError),
 }
 
 /// SMTP section. Used to configure the SMTP server
 #[derive(Debug, Deserialize)]
 pub struct SmtpConfig {
-    /// IP address to listen on
+    /// IP address to listen on, used when `listen` is unset
+    #[serde(default = "default_listen_addr")]
     listen_addr: IpAddr,
-    /// Port to listen on
+    /// Port to listen on, used when `listen` is unset
+    #[serde(default = "default_smtp_port")]
     listen_port: u16,
+    /// Addresses to listen on, as `ip:port` strings
+    listen: Option<Vec<SocketAddr>>,
+    /// Unix domain socket to listen on
+    /// Not supported yet, since samotop's services only handle TCP streams
+    listen_unix: Option<PathBuf>,
     /// Server name
     /// Returned to the SMTP client
     pub service_name: Option<String>,
+    /// How bare LF line endings in the message data are treated
+    #[serde(default)]
+    pub line_endings: LineEndings,
+    /// How sender addresses are normalized for the allowed senders, dedup and annotations
+    #[serde(default)]
+    pub sender_normalization: SenderNormalization,
+    /// Control file that turns away all mail with a temporary failure while it exists
+    pub pause_file: Option<PathBuf>,
+    /// TCP keep-alive interval in seconds for accepted connections
+    /// The OS default is used when unset
+    keepalive_secs: Option<u64>,
+    /// Domains recipients are accepted for, with `*.` matching subdomains
+    /// Every domain is accepted when empty
+    #[serde(default)]
+    pub served_domains: ServedDomains,
+    /// Senders allowed to relay mail, as addresses or `@domain` suffixes
+    /// Every sender is allowed when unset
+    pub allowed_senders: Option<AllowedSenders>,
+    /// Size in bytes above which a message body aborts the transaction
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Number of messages a client may send on one connection before having to reconnect
+    /// Unlimited when unset
+    pub max_messages_per_connection: Option<usize>,
+    /// Recipient addresses that control the bridge, mapped to `ping`, `mute` or `stats`
+    /// Off when empty
+    #[serde(default)]
+    pub command_recipients: CommandRecipients,
+    /// Time in seconds a posted Message-ID is remembered, dropping repeats within it
+    /// Repeats are posted when unset
+    message_id_dedup_ttl_secs: Option<u64>,
+    /// Header fields a message must have, or it is refused with a permanent failure
+    #[serde(default)]
+    pub required_headers: Vec<String>,
+    /// Whether the time from connection to queueing is shown in the embed
+    #[serde(default)]
+    pub show_transaction_timing: bool,
+    /// Minimum average rate in bytes per second message data must arrive at
+    /// Slower transactions are aborted. Set to 0 to turn the check off
+    #[serde(default = "default_min_data_rate")]
+    pub min_data_rate: u64,
+    /// Time in seconds the message data rate is averaged over
+    #[serde(default = "default_data_rate_window_secs")]
+    data_rate_window_secs: u64,
+    /// Time in seconds message data may stop arriving before the connection is closed
+    /// This catches senders that stall before the rate is ever checked. Set to 0 to turn it off
+    #[serde(default = "default_data_idle_timeout_secs")]
+    data_idle_timeout_secs: u64,
+    /// Whether TLS is offered: `disabled`, `starttls`, `starttls_required` or `enabled`
+    /// This build's samotop has no TLS support, so anything but `disabled` fails validation
+    #[serde(default)]
+    tls_mode: TlsSetting,
+    /// PKCS#12 file holding the TLS certificate and key, required unless TLS is disabled
+    tls_cert_file: Option<PathBuf>,
+    /// Password protecting the TLS certificate file
+    tls_key_password: Option<String>,
+}
+
+/// Default port the SMTP server listens on
+pub const DEFAULT_SMTP_PORT: u16 = 2500;
+/// Default address the SMTP server listens on
+pub const DEFAULT_LISTEN_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+/// Default minimum rate message data must arrive at, in bytes per second
+pub const DEFAULT_MIN_DATA_RATE: u64 = 16;
+/// Default time the message data rate is averaged over, in seconds
+pub const DEFAULT_DATA_RATE_WINDOW_SECS: u64 = 60;
+/// Default time message data may stop arriving before the connection is closed, in seconds
+pub const DEFAULT_DATA_IDLE_TIMEOUT_SECS: u64 = 300;
+
+fn default_max_body_bytes() -> usize {
+    DEFAULT_MAX_BODY_BYTES
+}
+
+fn default_listen_addr() -> IpAddr {
+    DEFAULT_LISTEN_ADDR
+}
+
+fn default_smtp_port() -> u16 {
+    DEFAULT_SMTP_PORT
 }
-impl Into<SocketAddr> for &SmtpConfig {
-    fn into(self) -> SocketAddr {
-        SocketAddr::new(self.listen_addr, self.listen_port)
+
+fn default_min_data_rate() -> u64 {
+    DEFAULT_MIN_DATA_RATE
+}
+
+fn default_data_rate_window_secs() -> u64 {
+    DEFAULT_DATA_RATE_WINDOW_SECS
+}
+
+fn default_data_idle_timeout_secs() -> u64 {
+    DEFAULT_DATA_IDLE_TIMEOUT_SECS
+}
+
+impl Default for SmtpConfig {
+    /// Returns the SMTP section used when the config file has none, matching its serde defaults
+    fn default() -> Self {
+        Self {
+            listen_addr: default_listen_addr(),
+            listen_port: default_smtp_port(),
+            listen: None,
+            listen_unix: None,
+            service_name: None,
+            line_endings: LineEndings::default(),
+            sender_normalization: SenderNormalization::default(),
+            pause_file: None,
+            keepalive_secs: None,
+            served_domains: ServedDomains::default(),
+            allowed_senders: None,
+            max_body_bytes: default_max_body_bytes(),
+            max_messages_per_connection: None,
+            command_recipients: CommandRecipients::default(),
+            message_id_dedup_ttl_secs: None,
+            required_headers: Vec::new(),
+            show_transaction_timing: false,
+            min_data_rate: default_min_data_rate(),
+            data_rate_window_secs: default_data_rate_window_secs(),
+            data_idle_timeout_secs: default_data_idle_timeout_secs(),
+            tls_mode: TlsSetting::default(),
+            tls_cert_file: None,
+            tls_key_password: None,
+        }
     }
 }
-impl Into<SocketAddr> for SmtpConfig {
-    fn into(self) -> SocketAddr {
-        (&self).into()
+impl SmtpConfig {
+    /// Returns the addresses to listen on
+    ///
+    /// The `listen` list is used when set, and the `listen_addr` and `listen_port` pair
+    /// otherwise.
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        match &self.listen {
+            Some(listen) => listen.clone(),
+            None => vec![SocketAddr::new(self.listen_addr, self.listen_port)],
+        }
+    }
+
+    /// Checks that there is at least one address to listen on, and that all of them are usable
+    pub fn check_listen(&self) -> Result<(), SmtpConfigError> {
+        if let Some(path) = &self.listen_unix {
+            return Err(SmtpConfigError::UnixListenUnsupported(path.clone()));
+        }
+        if self.socket_addrs().is_empty() {
+            return Err(SmtpConfigError::NoListenAddrs);
+        }
+        Ok(())
+    }
+
+    /// Checks that the TLS settings are complete and can be served
+    ///
+    /// samotop is built without its `tls` feature, so it would silently serve plain text
+    /// whatever the mode. Turning TLS on is refused rather than leaving mail unencrypted.
+    pub fn check_tls(&self) -> Result<(), SmtpConfigError> {
+        if self.tls_mode == TlsSetting::Disabled {
+            return Ok(());
+        }
+        if self.tls_cert_file.is_none() {
+            return Err(SmtpConfigError::MissingTlsCertFile);
+        }
+        Err(SmtpConfigError::TlsUnsupported(self.tls_mode))
+    }
+
+    /// Returns the TLS settings for the SMTP service
+    pub fn tls_config(&self) -> TlsConfig {
+        match &self.tls_cert_file {
+            Some(file) if self.tls_mode != TlsSetting::Disabled => TlsConfig {
+                mode: self.tls_mode.into(),
+                id: TlsIdFile {
+                    file: file.clone(),
+                    password: self
+                        .tls_key_password
+                        .clone()
+                        .map(|password| password.into_bytes().into()),
+                },
+            },
+            _ => tls_config_none(),
+        }
+    }
+
+    /// Returns the TCP keep-alive interval for accepted connections, if set
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive_secs.map(Duration::from_secs)
+    }
+
+    /// Returns the time the message data rate is averaged over
+    pub fn data_rate_window(&self) -> Duration {
+        Duration::from_secs(self.data_rate_window_secs)
+    }
+
+    /// Returns how long message data may stop arriving before the connection is closed, if limited
+    pub fn data_idle_timeout(&self) -> Option<Duration> {
+        Some(self.data_idle_timeout_secs)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
     }
+
+    /// Returns how long a posted Message-ID is remembered, if repeats are dropped
+    pub fn message_id_dedup_ttl(&self) -> Option<Duration> {
+        self.message_id_dedup_ttl_secs.map(Duration::from_secs)
+    }
+}
+/// Errors in the SMTP section
+#[derive(Debug)]
+pub enum SmtpConfigError {
+    /// TLS is turned on without a certificate file
+    MissingTlsCertFile,
+    /// TLS is turned on, which this build can't serve
+    TlsUnsupported(TlsSetting),
+    /// The `listen` list is empty
+    NoListenAddrs,
+    /// A Unix socket listener was configured, which this build can't serve
+    UnixListenUnsupported(PathBuf),
 }
 
-/// Discord section. Used to configure the Discord webhook
+/// Filter section. Used to pipe mail through an external command
 #[derive(Debug, Deserialize)]
+pub struct FilterConfig {
+    /// Path to the command
+    pub command: String,
+    /// Arguments passed to the command
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// How long the command may run, in milliseconds
+    #[serde(default = "default_filter_timeout_ms")]
+    timeout_ms: u64,
+}
+impl FilterConfig {
+    /// Returns how long the command may run before it is killed
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Default time an external filter may run, in milliseconds
+pub const DEFAULT_FILTER_TIMEOUT_MS: u64 = 5000;
+
+fn default_filter_timeout_ms() -> u64 {
+    DEFAULT_FILTER_TIMEOUT_MS
+}
+
+/// Format section. Used to configure how mail is laid out in Discord
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FormatConfig {
+    /// How the mail body is laid out in the embed
+    body_layout: BodyLayout,
+    /// How a long mail body is split into sections
+    section_split: SectionSplit,
+    /// Whether the From field is hidden behind a spoiler
+    spoiler_from: bool,
+    /// Whether the To field is hidden behind a spoiler
+    spoiler_to: bool,
+    /// Whether to show the message size
+    show_size: bool,
+    /// Whether to show the Content-Language header, when the mail has one
+    show_language: bool,
+    /// Whether to show the Sender header, when it differs from the From header
+    show_sender: bool,
+    /// What to show as the sender when neither a From header nor an envelope sender is present
+    unknown_sender: Option<String>,
+    /// Where the embed author links to, either `mailto` or a URL template containing `{address}`
+    /// The sender isn't shown as the author when unset
+    author_url: Option<String>,
+    /// How the webhook username is chosen for each message
+    username_mode: UsernameMode,
+    /// Webhook username used by the static username mode
+    username: Option<String>,
+    /// Which fields are shown and in what order, each with a source, an optional label and a style
+    /// The default From, To, Size, Language and Body fields are used when unset
+    fields: Option<Vec<FieldSpec>>,
+    /// Whether the first and last hops of the Received trace are shown in the footer
+    show_received_trace: bool,
+    /// Where the subject is shown: the embed title, the message content, or both
+    subject_placement: SubjectPlacement,
+    /// What the title shows when the subject is missing or blank: `default_title` or `first_line`
+    subject_fallback: SubjectFallback,
+    /// Whether URLs in the body are wrapped in angle brackets to stop link previews
+    suppress_link_previews: bool,
+    /// Whether aligned plain text tables in the body are put in code blocks
+    fence_tables: bool,
+    /// Whether a body too long for one message is posted across continuation messages
+    /// The body is cut short when unset
+    split_long_bodies: bool,
+    /// Most messages a split body is posted across, past which the rest is left out
+    /// Defaults to 5
+    max_split_messages: Option<usize>,
+    /// How many lines of the body are shown, with the whole body attached as `body.txt` when it
+    /// is longer
+    /// The whole body is shown, or cut short, when unset
+    body_preview_lines: Option<usize>,
+    /// TOML file mapping sender addresses to extra fields added to their messages
+    annotations_file: Option<PathBuf>,
+    /// Whether the annotations file is read again when it changes
+    reload_annotations: bool,
+    /// What happens to a Bcc header left in a message: `redact` drops it, `show` adds a field
+    bcc: BccHandling,
+    /// Color of the embeds, such as `0xff8800`, unless a route sets its own
+    /// Discord's default is used when unset
+    embed_color: Option<u32>,
+}
+
+/// How the webhook username is chosen for each message
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsernameMode {
+    /// The webhook's own name is used
+    #[default]
+    Default,
+    /// The configured username is used for every message
+    Static,
+    /// The sender's display name or address is used
+    Sender,
+}
+impl FormatConfig {
+    /// Parses format settings laid over others, which fill in any they don't set
+    ///
+    /// # Parameters
+    /// * `base` - the settings inherited
+    /// * `overrides` - the settings used in their place
+    pub fn with_overrides(
+        base: &toml::value::Table,
+        overrides: &toml::value::Table,
+    ) -> Result<Self, FormatConfigError> {
+        let mut merged = base.clone();
+        merged.extend(overrides.clone());
+        toml::Value::Table(merged)
+            .try_into()
+            .map_err(FormatConfigError::Invalid)
+    }
+
+    /// Builds the default embed handler with these settings
+    ///
+    /// # Parameters
+    /// * `sender_normalization` - how senders are normalized before looking up annotations
+    pub fn embed_handler(
+        &self,
+        sender_normalization: &SenderNormalization,
+    ) -> Result<EmbedHandler, FormatConfigError> {
+        let handler = EmbedHandler::new()
+            .with_body_layout(self.body_layout)
+            .with_section_split(self.section_split)
+            .with_spoilers(self.spoiler_from, self.spoiler_to)
+            .with_size(self.show_size)
+            .with_language(self.show_language)
+            .with_sender(self.show_sender)
+            .with_received_trace(self.show_received_trace)
+            .with_subject_placement(self.subject_placement)
+            .with_subject_fallback(self.subject_fallback)
+            .with_link_previews_suppressed(self.suppress_link_previews)
+            .with_tables_fenced(self.fence_tables)
+            .with_long_bodies_split(self.split_long_bodies)
+            .with_bcc(self.bcc)
+            .with_username(self.webhook_username()?);
+        let handler = match &self.fields {
+            Some(fields) => handler.with_fields(fields.clone()),
+            None => handler,
+        };
+        let handler = match &self.annotations_file {
+            Some(path) => handler.with_annotations(Arc::new(
+                AnnotationLookup::load(path, self.reload_annotations, sender_normalization.clone())
+                    .map_err(FormatConfigError::Annotations)?,
+            )),
+            None => handler,
+        };
+        let handler = match self.embed_color {
+            Some(color) => handler.with_color(color),
+            None => handler,
+        };
+        let handler = match self.body_preview_lines {
+            Some(lines) => handler.with_body_preview_lines(lines),
+            None => handler,
+        };
+        let handler = match &self.unknown_sender {
+            Some(unknown_sender) => handler.with_unknown_sender(unknown_sender),
+            None => handler,
+        };
+        Ok(match &self.author_url {
+            Some(author_url) => handler.with_author_link(
+                AuthorLink::parse(author_url).map_err(FormatConfigError::AuthorLink)?,
+            ),
+            None => handler,
+        })
+    }
+
+    /// Returns whether a body too long for one message is posted across continuation messages
+    pub fn split_long_bodies(&self) -> bool {
+        self.split_long_bodies
+    }
+
+    /// Returns the most messages a split body is posted across
+    pub fn max_split_messages(&self) -> usize {
+        self.max_split_messages
+            .unwrap_or(DEFAULT_MAX_SPLIT_MESSAGES)
+    }
+
+    /// Returns how the webhook username is chosen, checking a static username was given
+    fn webhook_username(&self) -> Result<WebhookUsername, FormatConfigError> {
+        Ok(match self.username_mode {
+            UsernameMode::Default => WebhookUsername::Default,
+            UsernameMode::Static => match &self.username {
+                Some(username) if username.is_empty() => {
+                    return Err(FormatConfigError::MissingUsername)
+                }
+                Some(username) if !handler::username_allowed(username) => {
+                    return Err(FormatConfigError::InvalidUsername(username.clone()))
+                }
+                Some(username) => WebhookUsername::Static(username.clone()),
+                None => return Err(FormatConfigError::MissingUsername),
+            },
+            UsernameMode::Sender => WebhookUsername::Sender,
+        })
+    }
+}
+#[derive(Debug)]
+pub enum FormatConfigError {
+    /// The author URL setting is not valid
+    AuthorLink(AuthorLinkError),
+    /// The static username mode was chosen without a username
+    MissingUsername,
+    /// The static username is one Discord refuses, being too long or naming Discord
+    InvalidUsername(String),
+    /// The annotations file couldn't be loaded
+    Annotations(AnnotationError),
+    /// A route's format settings are not well formed
+    Invalid(toml::de::Error),
+}
+
+/// Discord section. Used to configure the Discord webhook
+#[derive(Deserialize)]
 pub struct DiscordConfig {
     webhook_url: Option<String>,
     webhook_id: Option<u64>,
     webhook_token: Option<String>,
+    /// Whether webhook URLs may use plain http
+    /// They must still be on a Discord host, and requests are always made to Discord over https
+    #[serde(default)]
+    allow_insecure_url: bool,
+    /// Environment variable holding the webhook token, used with `webhook_id`
+    webhook_token_env: Option<String>,
+    /// Environment variable holding the webhook URL
+    webhook_url_env: Option<String>,
+    /// File holding the webhook token, used with `webhook_id`
+    webhook_token_file: Option<PathBuf>,
+    /// Message content sent when the mail handler produces an empty message
+    pub empty_message: Option<String>,
+    /// Number of repeats after which an identical send failure is logged again
+    #[serde(default = "default_error_log_every")]
+    pub error_log_every: u64,
+    /// Time in seconds after which an identical send failure is logged again
+    #[serde(default = "default_error_log_interval_secs")]
+    error_log_interval_secs: u64,
+    /// Extra HTTP headers sent with every webhook request
+    #[serde(default)]
+    http_headers: BTreeMap<String, String>,
+    /// Time in seconds between background checks that the webhook still exists
+    /// Checks are off when unset
+    ping_interval_secs: Option<u64>,
+    /// Time in seconds between fetches of the webhook to pick up channel and name changes
+    /// The webhook is only fetched at startup when unset
+    refresh_interval_secs: Option<u64>,
+    /// Combined length of embed text above which the mail is uploaded under a short summary
+    /// Fields are dropped to fit instead when long bodies are split or the mail is too large
+    #[serde(default = "default_max_embed_chars")]
+    pub max_embed_chars: usize,
+    /// Recipient address or domain patterns mapped to the webhook URLs their mail is posted to
+    /// Local parts may use `*` wildcards, such as `alert-*@example.com`, for catch-all domains
+    /// A route may instead be a table with a `url`, an embed `color` and a `format` table
+    /// A route's `format` table sets any `format` settings differently for its mail, inheriting
+    /// the rest. Its mail is laid out by the embed handler, without going through the filter
+    #[serde(default)]
+    routes: BTreeMap<String, RouteConfig>,
+    /// What happens to mail for recipients no route matches
+    /// By default it goes to the default webhook
+    #[serde(default)]
+    pub routing_default: RoutingDefault,
+    /// Whether to post a notice to the webhook when the bridge starts
+    #[serde(default)]
+    pub startup_notice: bool,
+    /// Whether mail above a size is uploaded as a `message.eml` file under a short summary
+    /// Mail over Discord's 8 MiB upload limit is posted as usual, with a note
+    #[serde(default)]
+    pub attach_raw: bool,
+    /// Size in bytes above which mail is attached, if attaching is on
+    #[serde(default = "default_attach_raw_above")]
+    pub attach_raw_above: usize,
+    /// Whether uploaded files are bundled into a zip named after the mail's subject
+    /// Mail that is still over Discord's 8 MiB upload limit once zipped is posted with a note
+    #[serde(default)]
+    pub zip_attachments: bool,
+    /// Number of times a send that failed with a rate limit, server or connection error is retried
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    /// Delay in milliseconds before the first retry, doubling for each one after
+    #[serde(default = "default_retry_base_ms")]
+    retry_base_ms: u64,
+    /// Time in milliseconds after the first attempt beyond which a send isn't retried
+    /// This keeps a long outage from holding up the SMTP client indefinitely
+    #[serde(default = "default_retry_max_total_ms")]
+    retry_max_total_ms: u64,
+    /// Number of messages posted per window, above which mail is dropped
+    /// Posting isn't capped when unset
+    pub max_posts: Option<u64>,
+    /// Length in seconds of the window messages are counted over for the post cap
+    #[serde(default = "default_max_posts_window_secs")]
+    max_posts_window_secs: u64,
+    /// Time in seconds the thread a message was posted to is remembered
+    /// Each mail conversation is posted in its own thread when set. Replies are matched to the
+    /// messages named in their References and In-Reply-To headers, and any other mail starts a
+    /// thread named after its subject. Webhooks can only start threads in forum channels
+    conversation_threads_ttl_secs: Option<u64>,
+}
+
+/// A route's destination, either just a webhook URL or a table with more settings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RouteConfig {
+    /// The webhook URL the route's mail is posted to
+    Url(String),
+    /// The webhook URL along with how the route's mail looks
+    Table {
+        /// The webhook URL the route's mail is posted to
+        url: String,
+        /// Embed color used in place of `format.embed_color`, such as `0xff8800`
+        color: Option<u32>,
+        /// Format settings used in place of the `format` section's
+        format: Option<toml::value::Table>,
+    },
+}
+
+impl RouteConfig {
+    /// Returns the webhook URL the route's mail is posted to
+    fn url(&self) -> &str {
+        match self {
+            RouteConfig::Url(url) | RouteConfig::Table { url, .. } => url,
+        }
+    }
+
+    /// Returns the route's embed color, if it sets one
+    fn color(&self) -> Option<u32> {
+        match self {
+            RouteConfig::Url(_) => None,
+            RouteConfig::Table { color, .. } => *color,
+        }
+    }
+
+    /// Returns the route's own format settings, if it sets any
+    fn format(&self) -> Option<&toml::value::Table> {
+        match self {
+            RouteConfig::Url(_) => None,
+            RouteConfig::Table { format, .. } => format.as_ref(),
+        }
+    }
+}
+
+impl fmt::Debug for DiscordConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Webhook URLs carry the token, so they are redacted along with it
+        let redacted_routes: BTreeMap<&str, &str> = self
+            .routes
+            .keys()
+            .map(|pattern| (pattern.as_str(), REDACTED))
+            .collect();
+        // Header values are often credentials for a proxy in front of Discord
+        let redacted_headers: BTreeMap<&str, &str> = self
+            .http_headers
+            .keys()
+            .map(|name| (name.as_str(), REDACTED))
+            .collect();
+        f.debug_struct("DiscordConfig")
+            .field("webhook_url", &self.webhook_url.as_ref().map(|_| REDACTED))
+            .field("webhook_id", &self.webhook_id)
+            .field(
+                "webhook_token",
+                &self.webhook_token.as_ref().map(|_| REDACTED),
+            )
+            .field("allow_insecure_url", &self.allow_insecure_url)
+            .field("webhook_token_env", &self.webhook_token_env)
+            .field("webhook_url_env", &self.webhook_url_env)
+            .field("webhook_token_file", &self.webhook_token_file)
+            .field("empty_message", &self.empty_message)
+            .field("error_log_every", &self.error_log_every)
+            .field("error_log_interval_secs", &self.error_log_interval_secs)
+            .field("http_headers", &redacted_headers)
+            .field("ping_interval_secs", &self.ping_interval_secs)
+            .field("refresh_interval_secs", &self.refresh_interval_secs)
+            .field("max_embed_chars", &self.max_embed_chars)
+            .field("routes", &redacted_routes)
+            .field("routing_default", &self.routing_default)
+            .field("startup_notice", &self.startup_notice)
+            .field("attach_raw", &self.attach_raw)
+            .field("attach_raw_above", &self.attach_raw_above)
+            .field("zip_attachments", &self.zip_attachments)
+            .field("retry_attempts", &self.retry_attempts)
+            .field("retry_base_ms", &self.retry_base_ms)
+            .field("retry_max_total_ms", &self.retry_max_total_ms)
+            .field("max_posts", &self.max_posts)
+            .field("max_posts_window_secs", &self.max_posts_window_secs)
+            .field(
+                "conversation_threads_ttl_secs",
+                &self.conversation_threads_ttl_secs,
+            )
+            .finish()
+    }
+}
+
+/// Headers set for each request from its token and body, which configured values would break
+const RESERVED_HEADERS: [HeaderName; 3] = [AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE];
+
+/// Default length of the window messages are counted over for the post cap, in seconds
+pub const DEFAULT_MAX_POSTS_WINDOW_SECS: u64 = 60;
+
+fn default_max_posts_window_secs() -> u64 {
+    DEFAULT_MAX_POSTS_WINDOW_SECS
+}
+
+/// Default size in bytes above which mail is attached
+pub const DEFAULT_ATTACH_RAW_ABOVE: usize = 16 * 1024;
+
+fn default_attach_raw_above() -> usize {
+    DEFAULT_ATTACH_RAW_ABOVE
+}
+
+fn default_retry_attempts() -> u32 {
+    DEFAULT_RETRY_ATTEMPTS
+}
+
+fn default_retry_base_ms() -> u64 {
+    DEFAULT_RETRY_BASE.as_millis() as u64
+}
+
+fn default_retry_max_total_ms() -> u64 {
+    DEFAULT_RETRY_MAX_TOTAL.as_millis() as u64
+}
+
+fn default_max_embed_chars() -> usize {
+    DEFAULT_MAX_EMBED_CHARS
+}
+
+fn default_error_log_every() -> u64 {
+    DEFAULT_ERROR_LOG_EVERY
+}
+
+fn default_error_log_interval_secs() -> u64 {
+    DEFAULT_ERROR_LOG_INTERVAL.as_secs()
 }
 
 impl DiscordConfig {
+    /// Returns the time after which an identical send failure is logged again
+    pub fn error_log_interval(&self) -> Duration {
+        Duration::from_secs(self.error_log_interval_secs)
+    }
+
+    /// Returns the time between background checks that the webhook still exists, if enabled
+    pub fn ping_interval(&self) -> Option<Duration> {
+        self.ping_interval_secs.map(Duration::from_secs)
+    }
+
+    /// Returns the delay before the first retry of a failed send
+    pub fn retry_base(&self) -> Duration {
+        Duration::from_millis(self.retry_base_ms)
+    }
+
+    /// Returns the time after the first attempt beyond which a failed send isn't retried
+    pub fn retry_max_total(&self) -> Duration {
+        Duration::from_millis(self.retry_max_total_ms)
+    }
+
+    /// Returns the length of the window messages are counted over for the post cap
+    pub fn max_posts_window(&self) -> Duration {
+        Duration::from_secs(self.max_posts_window_secs)
+    }
+
+    /// Returns how long a conversation's thread is remembered, if conversations are threaded
+    pub fn conversation_threads_ttl(&self) -> Option<Duration> {
+        self.conversation_threads_ttl_secs.map(Duration::from_secs)
+    }
+
+    /// Returns the time between fetches of the webhook, if enabled
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        self.refresh_interval_secs.map(Duration::from_secs)
+    }
+
+    /// Returns the extra HTTP headers sent with every webhook request
+    ///
+    /// A `User-Agent` replaces the default one naming the bridge and its version. Headers set
+    /// from each request's token and body can't be overridden and are rejected.
+    pub fn http_headers(&self) -> Result<HeaderMap, DiscordConfigError> {
+        use DiscordConfigError::*;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.http_headers {
+            let header_name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| InvalidHeader(name.clone()))?;
+            if RESERVED_HEADERS.contains(&header_name) {
+                return Err(ReservedHeader(name.clone()));
+            }
+            let header_value =
+                HeaderValue::from_str(value).map_err(|_| InvalidHeader(name.clone()))?;
+            headers.insert(header_name, header_value);
+        }
+        Ok(headers)
+    }
+
+    /// Parses a webhook URL, accepting plain http only if allowed
+    ///
+    /// # Parameters
+    /// * `url` - Discord webhook url
+    fn parse_url(&self, url: &str) -> Result<DiscordWebhookAuth, DiscordWebhookAuthUrlError> {
+        if self.allow_insecure_url {
+            DiscordWebhookAuth::from_url_allow_insecure(url)
+        } else {
+            DiscordWebhookAuth::from_url(url)
+        }
+    }
+
+    /// Returns the recipient routes with their webhook id and token
+    ///
+    /// # Parameters
+    /// * `format` - the `format` section as written, which a route's own format is laid over
+    /// * `sender_normalization` - how senders are normalized before looking up annotations
+    pub fn routes(
+        &self,
+        format: &toml::value::Table,
+        sender_normalization: &SenderNormalization,
+    ) -> Result<Routes<RouteDestination>, DiscordConfigError> {
+        use DiscordConfigError::*;
+        let routes = self
+            .routes
+            .iter()
+            .map(|(pattern, route)| {
+                let auth = self
+                    .parse_url(route.url())
+                    .map_err(|e| RouteUrl(pattern.clone(), e))?;
+                let destination = RouteDestination::new(auth);
+                let destination = match route.color() {
+                    Some(color) => destination.with_color(color),
+                    None => destination,
+                };
+                let destination = match route.format() {
+                    Some(overrides) => destination.with_handler(
+                        FormatConfig::with_overrides(format, overrides)
+                            .and_then(|format| format.embed_handler(sender_normalization))
+                            .map_err(|e| RouteFormat(pattern.clone(), e))?,
+                    ),
+                    None => destination,
+                };
+                Ok((pattern.clone(), destination))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Routes::new(routes))
+    }
+
+    /// Returns the webhook id and token from the first complete credential source
+    ///
+    /// Sources are tried in this order:
+    /// 1. `webhook_id` and `webhook_token`
+    /// 2. `webhook_url`
+    /// 3. `webhook_id` and `webhook_token_file`
+    /// 4. `webhook_id` and the variable named by `webhook_token_env`
+    /// 5. the variable named by `webhook_url_env`
+    ///
+    /// A source that is complete but invalid is an error, rather than falling through to the next.
+    /// So is a named environment variable that isn't set.
     pub fn get_auth(&self) -> Result<DiscordWebhookAuth, DiscordConfigError> {
         use DiscordConfigError::*;
-        match (&self.webhook_url, self.webhook_id, &self.webhook_token) {
-            (None, None, None) => Err(NeitherUrlNorPartsSpecified),
-            (None, None, Some(_)) => Err(ConfigMissingWebhookId),
-            (None, Some(_), None) => Err(ConfigMissingWebhookToken),
-            (None, Some(id), Some(token)) => Ok(DiscordWebhookAuth::new(id, token.into())),
-            (Some(url), None, None) => DiscordWebhookAuth::from_url(url).map_err(UrlError),
-            (Some(_), Some(_), None) | (Some(_), None, Some(_)) | (Some(_), Some(_), Some(_)) => {
-                Err(InvalidParamCombination)
+        let mut tried = Vec::new();
+        match (self.webhook_id, &self.webhook_token) {
+            (Some(id), Some(token)) => return Ok(DiscordWebhookAuth::new(id, token.clone())),
+            (None, Some(_)) => tried.push("webhook_token is set without webhook_id"),
+            _ => tried.push("webhook_token is not set"),
+        }
+        match &self.webhook_url {
+            Some(url) => return self.parse_url(url).map_err(UrlError),
+            None => tried.push("webhook_url is not set"),
+        }
+        match (self.webhook_id, &self.webhook_token_file) {
+            (Some(id), Some(path)) => {
+                let token = fs::read_to_string(path)
+                    .map_err(|e| TokenFile(path.clone(), e))?
+                    .trim()
+                    .to_string();
+                return Ok(DiscordWebhookAuth::new(id, token));
             }
+            (None, Some(_)) => tried.push("webhook_token_file is set without webhook_id"),
+            _ => tried.push("webhook_token_file is not set"),
         }
+        match (self.webhook_id, &self.webhook_token_env) {
+            (Some(id), Some(var)) => {
+                let token = env::var(var).map_err(|_| EnvVarMissing(var.clone()))?;
+                return Ok(DiscordWebhookAuth::new(id, token));
+            }
+            (None, Some(_)) => tried.push("webhook_token_env is set without webhook_id"),
+            _ => tried.push("webhook_token_env is not set"),
+        }
+        match &self.webhook_url_env {
+            Some(var) => {
+                let url = env::var(var).map_err(|_| EnvVarMissing(var.clone()))?;
+                return self.parse_url(&url).map_err(UrlError);
+            }
+            None => tried.push("webhook_url_env is not set"),
+        }
+        Err(NoCredentials(tried))
     }
 }
+
 #[derive(Debug)]
 pub enum DiscordConfigError {
-    NeitherUrlNorPartsSpecified,
-    ConfigMissingWebhookId,
-    ConfigMissingWebhookToken,
-    InvalidParamCombination,
+    /// No credential source was complete, with the reason each one was passed over
+    NoCredentials(Vec<&'static str>),
+    /// The webhook token file couldn't be read
+    TokenFile(PathBuf, io::Error),
+    /// An environment variable named as a credential source isn't set
+    EnvVarMissing(String),
+    /// A route's webhook URL is invalid
+    RouteUrl(String, DiscordWebhookAuthUrlError),
+    /// A route's format settings are invalid
+    RouteFormat(String, FormatConfigError),
     UrlError(DiscordWebhookAuthUrlError),
+    /// An HTTP header name or value is not valid
+    InvalidHeader(String),
+    /// An HTTP header is set for each request and can't be configured
+    ReservedHeader(String),
+}
+
+impl fmt::Display for DiscordConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use DiscordConfigError::*;
+        match self {
+            NoCredentials(tried) => write!(
+                f,
+                "no Discord webhook credentials found ({})",
+                tried.join(", ")
+            ),
+            TokenFile(path, e) => write!(
+                f,
+                "failed to read webhook token file {}: {}",
+                path.display(),
+                e
+            ),
+            EnvVarMissing(var) => write!(f, "environment variable {} isn't set", var),
+            RouteUrl(pattern, e) => write!(f, "route {:?}: {}", pattern, e),
+            RouteFormat(pattern, e) => write!(f, "route {:?} format: {:?}", pattern, e),
+            UrlError(e) => write!(f, "{}", e),
+            InvalidHeader(name) => write!(f, "invalid HTTP header {:?}", name),
+            ReservedHeader(name) => write!(f, "HTTP header {:?} can't be configured", name),
+        }
+    }
+}
+
+impl error::Error for DiscordConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use DiscordConfigError::*;
+        match self {
+            TokenFile(_, e) => Some(e),
+            RouteUrl(_, e) | UrlError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::USER_AGENT;
+    use samotop::model::command::{SmtpAddress, SmtpHost, SmtpPath};
+    use std::process;
+
+    /// Parses a config, panicking if it isn't well formed
+    ///
+    /// # Parameters
+    /// * `toml` - the config file contents
+    fn parse(toml: &str) -> Config {
+        Config::from_slice(toml.as_bytes()).expect("well formed config")
+    }
+
+    #[test]
+    fn user_agent_can_be_configured() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_id = 1
+            webhook_token = "token"
+            http_headers = { "User-Agent" = "monitor/1.0" }
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        let headers = config.discord.http_headers().expect("valid headers");
+        assert_eq!(headers[USER_AGENT], "monitor/1.0");
+    }
+
+    #[test]
+    fn static_username_naming_discord_is_refused() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_id = 1
+            webhook_token = "token"
+
+            [format]
+            username_mode = "static"
+            username = "Discord Relay"
+            "#,
+        );
+        let normalization = SenderNormalization::default();
+        match config.format.embed_handler(&normalization) {
+            Err(FormatConfigError::InvalidUsername(username)) => {
+                assert_eq!(username, "Discord Relay")
+            }
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reserved_header_fails_validation() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_id = 1
+            webhook_token = "token"
+            http_headers = { "Authorization" = "Bot secret" }
+            "#,
+        );
+        match config.validate() {
+            Err(ConfigError::Auth(
+                "discord.http_headers",
+                DiscordConfigError::ReservedHeader(_),
+            )) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    /// Returns the webhook id and token a Discord section resolves to
+    ///
+    /// # Parameters
+    /// * `discord` - the Discord section's TOML
+    fn auth(discord: &str) -> (u64, String) {
+        let config: DiscordConfig = toml::from_str(discord).expect("valid Discord section");
+        let auth = config.get_auth().expect("credentials");
+        (auth.id, auth.token)
+    }
+
+    #[test]
+    fn inline_token_wins_over_every_other_source() {
+        let discord = r#"
+            webhook_id = 1
+            webhook_token = "inline"
+            webhook_url = "https://discord.com/api/webhooks/2/url"
+            webhook_token_file = "/nonexistent"
+            "#;
+        assert_eq!(auth(discord), (1, "inline".into()));
+    }
+
+    #[test]
+    fn url_wins_over_the_token_file_and_environment() {
+        let discord = r#"
+            webhook_id = 1
+            webhook_url = "https://discord.com/api/webhooks/2/url"
+            webhook_token_file = "/nonexistent"
+            webhook_token_env = "SMTP_DISCORD_BRIDGE_TEST_UNSET"
+            "#;
+        assert_eq!(auth(discord), (2, "url".into()));
+    }
+
+    #[test]
+    fn token_file_wins_over_the_environment() {
+        let path = env::temp_dir().join(format!("smtp_discord_bridge_{}_token", process::id()));
+        fs::write(&path, "file\n").unwrap();
+        let discord = format!(
+            r#"
+            webhook_id = 1
+            webhook_token_file = "{}"
+            webhook_token_env = "SMTP_DISCORD_BRIDGE_TEST_UNSET"
+            "#,
+            path.display()
+        );
+        let result = auth(&discord);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result, (1, "file".into()));
+    }
+
+    #[test]
+    fn token_environment_variable_wins_over_the_url_variable() {
+        env::set_var("SMTP_DISCORD_BRIDGE_TEST_TOKEN", "env");
+        let discord = r#"
+            webhook_id = 1
+            webhook_token_env = "SMTP_DISCORD_BRIDGE_TEST_TOKEN"
+            webhook_url_env = "SMTP_DISCORD_BRIDGE_TEST_UNSET"
+            "#;
+        assert_eq!(auth(discord), (1, "env".into()));
+    }
+
+    #[test]
+    fn url_environment_variable_is_the_last_source() {
+        env::set_var(
+            "SMTP_DISCORD_BRIDGE_TEST_URL",
+            "https://discord.com/api/webhooks/3/env-url",
+        );
+        let discord = r#"webhook_url_env = "SMTP_DISCORD_BRIDGE_TEST_URL""#;
+        assert_eq!(auth(discord), (3, "env-url".into()));
+    }
+
+    #[test]
+    fn missing_credentials_list_every_source() {
+        let config: DiscordConfig = toml::from_str("").unwrap();
+        match config.get_auth() {
+            Err(DiscordConfigError::NoCredentials(tried)) => assert_eq!(tried.len(), 5),
+            result => panic!("unexpected result {:?}", result.map(|auth| auth.id)),
+        }
+    }
+
+    #[test]
+    fn tls_modes_other_than_disabled_fail_validation() {
+        let config = parse(
+            r#"
+            [smtp]
+            tls_mode = "starttls"
+            tls_cert_file = "/etc/ssl/bridge.p12"
+
+            [discord]
+            webhook_id = 1
+            webhook_token = "token"
+            "#,
+        );
+        match config.validate() {
+            Err(ConfigError::Smtp(
+                "smtp.tls_mode",
+                SmtpConfigError::TlsUnsupported(TlsSetting::Starttls),
+            )) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn missing_smtp_section_uses_the_defaults() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_id = 1
+            webhook_token = "token"
+            "#,
+        );
+        assert_eq!(
+            config.smtp.socket_addrs(),
+            vec![SocketAddr::new(DEFAULT_LISTEN_ADDR, DEFAULT_SMTP_PORT)]
+        );
+        assert_eq!(config.smtp.max_body_bytes, DEFAULT_MAX_BODY_BYTES);
+        assert_eq!(
+            config.smtp.data_idle_timeout(),
+            Some(Duration::from_secs(DEFAULT_DATA_IDLE_TIMEOUT_SECS))
+        );
+    }
+
+    #[test]
+    fn smtp_section_fragment_fills_in_the_rest() {
+        let config = parse(
+            r#"
+            [smtp]
+            listen_addr = "0.0.0.0"
+
+            [discord]
+            webhook_id = 1
+            webhook_token = "token"
+            "#,
+        );
+        assert_eq!(
+            config.smtp.socket_addrs(),
+            vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                DEFAULT_SMTP_PORT
+            )]
+        );
+        assert_eq!(config.smtp.min_data_rate, DEFAULT_MIN_DATA_RATE);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn debug_output_has_no_secrets() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_url = "https://discord.com/api/webhooks/1/url-secret"
+            webhook_token = "token-secret"
+            http_headers = { "Proxy-Authorization" = "Basic header-secret" }
+            routes = { "alerts@example.com" = "https://discord.com/api/webhooks/2/route-secret" }
+            "#,
+        );
+        let debug = format!("{:?}", config);
+        for secret in &[
+            "url-secret",
+            "token-secret",
+            "header-secret",
+            "route-secret",
+        ] {
+            assert!(!debug.contains(secret), "{} in {}", secret, debug);
+        }
+        assert!(debug.contains("Proxy-Authorization"));
+    }
+
+    #[test]
+    fn routes_may_set_a_color() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_url = "https://discord.com/api/webhooks/1/token"
+            [discord.routes]
+            "alerts@example.com" = { url = "https://discord.com/api/webhooks/2/token", color = 0xff0000 }
+            "@example.org" = "https://discord.com/api/webhooks/3/token"
+            "#,
+        );
+        let routes = config.routes().expect("valid routes");
+        let color = |local: &str, domain: &str| {
+            routes
+                .resolve(&SmtpPath::Direct(SmtpAddress::Mailbox(
+                    local.into(),
+                    SmtpHost::Domain(domain.into()),
+                )))
+                .map(|destination| destination.color)
+        };
+        assert_eq!(color("alerts", "example.com"), Some(Some(0xff0000)));
+        assert_eq!(color("alerts", "example.org"), Some(None));
+    }
+
+    #[test]
+    fn route_format_inherits_the_format_section() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_url = "https://discord.com/api/webhooks/1/token"
+            [discord.routes]
+            "alerts@example.com" = { url = "https://discord.com/api/webhooks/2/token", format = { embed_color = 0xff0000, fields = [{ source = "subject" }] } }
+            "@example.org" = "https://discord.com/api/webhooks/3/token"
+            [format]
+            show_size = true
+            embed_color = 0x00ff00
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        let overrides = config.discord.routes["alerts@example.com"]
+            .format()
+            .expect("route format");
+        let format =
+            FormatConfig::with_overrides(&config.format_table, overrides).expect("valid format");
+        assert!(format.show_size);
+        assert_eq!(format.embed_color, Some(0xff0000));
+        assert_eq!(
+            format.fields,
+            Some(vec![FieldSpec::new(handler::FieldSource::Subject)])
+        );
+        let routes = config.routes().expect("valid routes");
+        let has_handler = |local: &str, domain: &str| {
+            routes
+                .resolve(&SmtpPath::Direct(SmtpAddress::Mailbox(
+                    local.into(),
+                    SmtpHost::Domain(domain.into()),
+                )))
+                .map(|destination| destination.handler.is_some())
+        };
+        assert_eq!(has_handler("alerts", "example.com"), Some(true));
+        assert_eq!(has_handler("alerts", "example.org"), Some(false));
+    }
+
+    #[test]
+    fn bad_route_format_is_rejected() {
+        let config = parse(
+            r#"
+            [discord]
+            webhook_url = "https://discord.com/api/webhooks/1/token"
+            [discord.routes]
+            "alerts@example.com" = { url = "https://discord.com/api/webhooks/2/token", format = { body_layout = 5 } }
+            "#,
+        );
+        match config.validate() {
+            Err(ConfigError::Auth(_, DiscordConfigError::RouteFormat(pattern, _))) => {
+                assert_eq!(pattern, "alerts@example.com")
+            }
+            other => panic!("expected a route format error, got {:?}", other),
+        }
+    }
 }
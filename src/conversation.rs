@@ -0,0 +1,153 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::headers::Headers;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Remembers which Discord thread each mail conversation was posted to on each webhook
+pub struct ConversationThreads {
+    /// How long a posted Message-ID's thread is remembered
+    ttl: Duration,
+    /// Thread each webhook id and Message-ID was posted to, and when
+    threads: HashMap<(u64, String), (u64, Instant)>,
+}
+
+impl ConversationThreads {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `ttl` - how long a posted Message-ID's thread is remembered
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Finds the thread a reply belongs in on a webhook
+    ///
+    /// The most recent Message-ID with a known thread wins.
+    ///
+    /// # Parameters
+    /// * `webhook_id` - the webhook the reply is posted with
+    /// * `references` - the Message-IDs the reply refers to, oldest first
+    pub fn find(&mut self, webhook_id: u64, references: &[String]) -> Option<u64> {
+        self.evict_expired();
+        references.iter().rev().find_map(|message_id| {
+            self.threads
+                .get(&(webhook_id, message_id.clone()))
+                .map(|(thread_id, _)| *thread_id)
+        })
+    }
+
+    /// Remembers the thread a message was posted to, so replies to it follow
+    ///
+    /// # Parameters
+    /// * `webhook_id` - the webhook the message was posted with
+    /// * `message_id` - the message's Message-ID
+    /// * `thread_id` - the thread it was posted to
+    pub fn record(&mut self, webhook_id: u64, message_id: &str, thread_id: u64) {
+        self.threads.insert(
+            (webhook_id, message_id.trim().into()),
+            (thread_id, Instant::now()),
+        );
+    }
+
+    /// Forgets threads posted to longer ago than the TTL
+    fn evict_expired(&mut self) {
+        let ttl = self.ttl;
+        self.threads.retain(|_, (_, posted)| posted.elapsed() < ttl);
+    }
+}
+
+/// Lists the Message-IDs a mail refers to, oldest first
+///
+/// References lists the conversation from its start, and In-Reply-To names the direct parent,
+/// which goes last.
+///
+/// # Parameters
+/// * `headers` - the mail's headers
+pub fn references(headers: &Headers) -> Vec<String> {
+    let mut references = Vec::new();
+    for name in &["References", "In-Reply-To"] {
+        for value in headers.get_all(name) {
+            for message_id in message_ids(value) {
+                if !references.contains(&message_id) {
+                    references.push(message_id);
+                }
+            }
+        }
+    }
+    references
+}
+
+/// Picks the bracketed Message-IDs out of a header value
+///
+/// # Parameters
+/// * `value` - the header value
+fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|rest| rest.find('>').map(|end| format!("<{}>", &rest[..end])))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn reply_finds_the_thread_of_the_message_it_refers_to() {
+        let mut threads = ConversationThreads::new(Duration::from_secs(60));
+        threads.record(1, "<first@example.com>", 10);
+        let references = vec!["<first@example.com>".into(), "<unknown@example.com>".into()];
+        assert_eq!(threads.find(1, &references), Some(10));
+        // Threads are kept apart per webhook
+        assert_eq!(threads.find(2, &references), None);
+    }
+
+    #[test]
+    fn most_recent_reference_wins() {
+        let mut threads = ConversationThreads::new(Duration::from_secs(60));
+        threads.record(1, "<first@example.com>", 10);
+        threads.record(1, "<second@example.com>", 20);
+        let references = vec!["<first@example.com>".into(), "<second@example.com>".into()];
+        assert_eq!(threads.find(1, &references), Some(20));
+    }
+
+    #[test]
+    fn thread_is_forgotten_after_the_ttl() {
+        let mut threads = ConversationThreads::new(Duration::from_millis(10));
+        threads.record(1, "<first@example.com>", 10);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(threads.find(1, &["<first@example.com>".into()]), None);
+    }
+
+    #[test]
+    fn references_come_before_the_direct_parent() {
+        let (headers, _) = Headers::parse(
+            b"In-Reply-To: <second@example.com>\r\n\
+              References: <first@example.com>\r\n <second@example.com>\r\n\r\n",
+        );
+        assert_eq!(
+            references(&headers),
+            vec!["<first@example.com>", "<second@example.com>"]
+        );
+    }
+}
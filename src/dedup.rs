@@ -0,0 +1,117 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Remembers recently posted Message-IDs so repeat deliveries can be dropped
+pub struct MessageIdCache {
+    /// How long a Message-ID is remembered
+    ttl: Duration,
+    /// When each remembered Message-ID was posted
+    seen: HashMap<String, Instant>,
+}
+
+impl MessageIdCache {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `ttl` - how long a Message-ID is remembered
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Checks whether a Message-ID was posted within the TTL
+    ///
+    /// # Parameters
+    /// * `message_id` - the Message-ID header value
+    pub fn is_duplicate(&mut self, message_id: &str) -> bool {
+        self.evict_expired();
+        self.seen.contains_key(message_id.trim())
+    }
+
+    /// Remembers that a Message-ID was posted
+    ///
+    /// # Parameters
+    /// * `message_id` - the Message-ID header value
+    pub fn record(&mut self, message_id: &str) {
+        self.seen.insert(message_id.trim().into(), Instant::now());
+    }
+
+    /// Remembers a Message-ID about to be posted, unless it was posted within the TTL
+    ///
+    /// Returns whether the Message-ID was free. A reserved Message-ID is a duplicate from then on,
+    /// so a repeat delivery arriving while the first is still being posted is dropped too.
+    ///
+    /// # Parameters
+    /// * `message_id` - the Message-ID header value
+    pub fn reserve(&mut self, message_id: &str) -> bool {
+        if self.is_duplicate(message_id) {
+            return false;
+        }
+        self.record(message_id);
+        true
+    }
+
+    /// Forgets a reserved Message-ID whose message wasn't posted, so it can be retried
+    ///
+    /// # Parameters
+    /// * `message_id` - the Message-ID header value
+    pub fn release(&mut self, message_id: &str) {
+        self.seen.remove(message_id.trim());
+    }
+
+    /// Forgets Message-IDs older than the TTL
+    fn evict_expired(&mut self) {
+        let ttl = self.ttl;
+        self.seen.retain(|_, posted| posted.elapsed() < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn repeated_message_id_is_a_duplicate() {
+        let mut cache = MessageIdCache::new(Duration::from_secs(60));
+        assert!(!cache.is_duplicate("<1@example.com>"));
+        cache.record("<1@example.com>");
+        assert!(cache.is_duplicate(" <1@example.com>"));
+        assert!(!cache.is_duplicate("<2@example.com>"));
+    }
+
+    #[test]
+    fn reserved_message_id_is_a_duplicate_until_released() {
+        let mut cache = MessageIdCache::new(Duration::from_secs(60));
+        assert!(cache.reserve("<1@example.com>"));
+        assert!(!cache.reserve("<1@example.com>"));
+        cache.release(" <1@example.com>");
+        assert!(cache.reserve("<1@example.com>"));
+    }
+
+    #[test]
+    fn message_id_is_forgotten_after_the_ttl() {
+        let mut cache = MessageIdCache::new(Duration::from_millis(10));
+        cache.record("<1@example.com>");
+        thread::sleep(Duration::from_millis(20));
+        assert!(!cache.is_duplicate("<1@example.com>"));
+    }
+}
@@ -1,12 +1,113 @@
+use serde::Deserialize;
+use std::error;
+use std::fmt;
 use std::num;
+use std::str::FromStr;
 use url::Url;
 
+/// Hosts Discord serves webhooks from
+pub const DISCORD_HOSTS: [&str; 6] = [
+    "discord.com",
+    "discordapp.com",
+    "ptb.discord.com",
+    "ptb.discordapp.com",
+    "canary.discord.com",
+    "canary.discordapp.com",
+];
+
+/// Scheme and host of webhook URLs built from an id and token
+pub const DEFAULT_ORIGIN: &str = "https://discord.com";
+
+/// Largest file Discord accepts in a webhook upload
+pub const MAX_UPLOAD_BYTES: usize = 8 * 1024 * 1024;
+
+/// A file uploaded along with a webhook message
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    /// Name the file is shown with in Discord
+    pub file_name: String,
+    /// MIME type of the file
+    pub mime_type: String,
+    /// The file's contents
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `file_name` - name the file is shown with in Discord
+    /// * `mime_type` - MIME type of the file
+    /// * `data` - the file's contents
+    pub fn new(file_name: &str, mime_type: &str, data: Vec<u8>) -> Self {
+        Self {
+            file_name: file_name.into(),
+            mime_type: mime_type.into(),
+            data,
+        }
+    }
+
+    /// Builds a multipart form body holding a webhook message and this file
+    ///
+    /// Returns the body and the boundary between its parts, which is picked so it doesn't
+    /// appear in the file.
+    ///
+    /// # Parameters
+    /// * `payload_json` - the webhook message as JSON
+    pub fn multipart_body(&self, payload_json: &str) -> (Vec<u8>, String) {
+        let mut boundary = String::from("smtp-discord-bridge-boundary");
+        while contains(&self.data, boundary.as_bytes()) {
+            boundary.push('-');
+        }
+        let file_name = self.file_name.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut body = Vec::with_capacity(self.data.len() + payload_json.len() + 512);
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\
+                 Content-Type: application/json\r\n\r\n{}\r\n",
+                boundary, payload_json
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                 Content-Type: {}\r\n\r\n",
+                boundary, file_name, self.mime_type
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&self.data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        (body, boundary)
+    }
+}
+
+/// Checks whether some bytes contain a sequence
+///
+/// # Parameters
+/// * `haystack` - the bytes to search
+/// * `needle` - the sequence to find
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Shown in place of secrets in debug output
+pub const REDACTED: &str = "***";
+
 /// Identifying and authentication info for a Discord webhook
+#[derive(Clone)]
 pub struct DiscordWebhookAuth {
     /// Discord webhook id
     pub id: u64,
     /// Discord webhook token
     pub token: String,
+    /// Thread in the webhook's channel to post to, if any
+    pub thread_id: Option<u64>,
+    /// Scheme and host the webhook URL used, such as `https://canary.discord.com`
+    pub origin: String,
 }
 impl DiscordWebhookAuth {
     /// Constructor
@@ -15,22 +116,68 @@ impl DiscordWebhookAuth {
     /// * `id` - Discord webhook id
     /// * `token` - Discord webhook token
     pub fn new(id: u64, token: String) -> Self {
-        Self { id, token }
+        Self {
+            id,
+            token,
+            thread_id: None,
+            origin: DEFAULT_ORIGIN.into(),
+        }
+    }
+
+    /// Builds the webhook URL on the host it was parsed from, including the thread if there is one
+    pub fn to_url(&self) -> String {
+        let url = format!("{}/api/webhooks/{}/{}", self.origin, self.id, self.token);
+        match self.thread_id {
+            Some(thread_id) => format!("{}?thread_id={}", url, thread_id),
+            None => url,
+        }
     }
 
     /// Parse the relevant fields of out a Discord webhook url
     ///
+    /// Only https URLs on one of Discord's hosts are accepted, since the token would otherwise be
+    /// sent in plain text or to someone else.
+    ///
     /// # Parameters
     /// * `url` - Discord webhook url
     pub fn from_url(url: &str) -> Result<Self, DiscordWebhookAuthUrlError> {
+        Self::parse_url(url, false)
+    }
+
+    /// Parse the relevant fields of out a Discord webhook url, accepting any scheme
+    ///
+    /// The host must still be one of Discord's. Only the id, token and thread are taken from the
+    /// URL, and requests are always made to Discord's API over https.
+    ///
+    /// # Parameters
+    /// * `url` - Discord webhook url
+    pub fn from_url_allow_insecure(url: &str) -> Result<Self, DiscordWebhookAuthUrlError> {
+        Self::parse_url(url, true)
+    }
+
+    /// Parse the relevant fields of out a Discord webhook url
+    ///
+    /// # Parameters
+    /// * `url` - Discord webhook url
+    /// * `allow_insecure` - whether schemes other than https are accepted
+    fn parse_url(url: &str, allow_insecure: bool) -> Result<Self, DiscordWebhookAuthUrlError> {
         use DiscordWebhookAuthUrlError::*;
         // Parse the url
         // As of 2020 06 23, the format is
         // https://discord.com/api/webhooks/ID/TOKEN
         let url = Url::parse(url).map_err(UrlParseError)?;
-        // Skip schema but you really should be using https
-        // Skip hostname since discord may change
-        let mut path_segments = url.path_segments().ok_or_else(|| UrlMissingPath)?;
+        if !allow_insecure && url.scheme() != "https" {
+            return Err(InsecureScheme(url.scheme().into()));
+        }
+        // Requests only ever go to Discord, so a URL for any other host would be silently ignored
+        let host = url.host_str().unwrap_or("");
+        if !DISCORD_HOSTS
+            .iter()
+            .any(|discord_host| host.eq_ignore_ascii_case(discord_host))
+        {
+            return Err(UnexpectedHost(host.into()));
+        }
+        let mut path_segments = url.path_segments().ok_or(UrlMissingPath)?;
         if path_segments.next() != Some("api") {
             Err(UrlPathMissingApi)
         } else if path_segments.next() != Some("webhooks") {
@@ -39,7 +186,18 @@ impl DiscordWebhookAuth {
             if let Some(id) = path_segments.next() {
                 let id: u64 = id.parse().map_err(IdParseError)?;
                 if let Some(token) = path_segments.next() {
-                    Ok(Self::new(id, token.into()))
+                    // The query, such as ?wait=true or ?thread_id=ID, is kept apart from the token
+                    let thread_id = match url.query_pairs().find(|(key, _)| key == "thread_id") {
+                        Some((_, thread_id)) => {
+                            Some(thread_id.parse().map_err(ThreadIdParseError)?)
+                        }
+                        None => None,
+                    };
+                    Ok(Self {
+                        thread_id,
+                        origin: url.origin().ascii_serialization(),
+                        ..Self::new(id, token.into())
+                    })
                 } else {
                     Err(UrlPathMissingToken)
                 }
@@ -50,11 +208,34 @@ impl DiscordWebhookAuth {
     }
 }
 
+impl FromStr for DiscordWebhookAuth {
+    type Err = DiscordWebhookAuthUrlError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        Self::from_url(url)
+    }
+}
+
+impl fmt::Debug for DiscordWebhookAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiscordWebhookAuth")
+            .field("id", &self.id)
+            .field("token", &REDACTED)
+            .field("thread_id", &self.thread_id)
+            .field("origin", &self.origin)
+            .finish()
+    }
+}
+
 /// Error parsing a URL to get the Discord webhook auth info
 #[derive(Debug)]
 pub enum DiscordWebhookAuthUrlError {
     /// Failed to parse the URL at all
     UrlParseError(url::ParseError),
+    /// Url doesn't use https
+    InsecureScheme(String),
+    /// Url isn't on one of Discord's hosts
+    UnexpectedHost(String),
     /// Url has no path
     UrlMissingPath,
     /// Url has no /api
@@ -67,4 +248,197 @@ pub enum DiscordWebhookAuthUrlError {
     IdParseError(num::ParseIntError),
     /// Url is missing /api/webhooks/ID/TOKEN
     UrlPathMissingToken,
+    /// Url has an invalid thread_id query parameter
+    ThreadIdParseError(num::ParseIntError),
+}
+
+impl fmt::Display for DiscordWebhookAuthUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use DiscordWebhookAuthUrlError::*;
+        match self {
+            UrlParseError(e) => write!(f, "invalid webhook URL: {}", e),
+            InsecureScheme(scheme) => write!(f, "webhook URL uses {} instead of https", scheme),
+            UnexpectedHost(host) => write!(f, "webhook URL host {:?} isn't a Discord host", host),
+            UrlMissingPath => write!(f, "webhook URL has no path"),
+            UrlPathMissingApi => write!(f, "webhook URL path doesn't start with /api"),
+            UrlPathMissingWebhooks => {
+                write!(f, "webhook URL path doesn't start with /api/webhooks")
+            }
+            UrlPathMissingId => write!(f, "webhook URL path has no webhook id"),
+            IdParseError(e) => write!(f, "invalid webhook id in URL: {}", e),
+            UrlPathMissingToken => write!(f, "webhook URL path has no webhook token"),
+            ThreadIdParseError(e) => write!(f, "invalid thread_id in webhook URL: {}", e),
+        }
+    }
+}
+
+impl error::Error for DiscordWebhookAuthUrlError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use DiscordWebhookAuthUrlError::*;
+        match self {
+            UrlParseError(e) => Some(e),
+            IdParseError(e) | ThreadIdParseError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Rules for normalizing sender addresses before they are matched or used as keys
+///
+/// This applies to the allowed senders, Message-ID dedup keys and annotation lookups, so that
+/// `Alice+tag@Example.COM` can be treated as `alice@example.com`. The domain is always
+/// lowercased since it is case insensitive.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SenderNormalization {
+    /// Lowercase the local part too
+    pub lowercase_local_part: bool,
+    /// Strip `+tag` suffixes from the local part
+    pub strip_plus_tags: bool,
+    /// Strip dots from the local part, as Gmail ignores them
+    pub strip_dots: bool,
+}
+
+impl SenderNormalization {
+    /// Normalizes a sender address into a key
+    ///
+    /// # Parameters
+    /// * `address` - sender address, optionally in angle brackets
+    pub fn normalize(&self, address: &str) -> String {
+        let address = address.trim().trim_start_matches('<').trim_end_matches('>');
+        let (local, domain) = match address.rfind('@') {
+            Some(at) => (&address[..at], Some(&address[at + 1..])),
+            None => (address, None),
+        };
+        let mut local = local.to_string();
+        if self.strip_plus_tags {
+            if let Some(plus) = local.find('+') {
+                local.truncate(plus);
+            }
+        }
+        if self.strip_dots {
+            local.retain(|c| c != '.');
+        }
+        if self.lowercase_local_part {
+            local = local.to_lowercase();
+        }
+        match domain {
+            Some(domain) => format!("{}@{}", local, domain.to_lowercase()),
+            None => local,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_normalization_only_lowercases_the_domain() {
+        let normalization = SenderNormalization::default();
+        assert_eq!(
+            normalization.normalize("<Alice+tag@Example.COM>"),
+            "Alice+tag@example.com"
+        );
+    }
+
+    #[test]
+    fn normalization_strips_tags_dots_and_case() {
+        let normalization = SenderNormalization {
+            lowercase_local_part: true,
+            strip_plus_tags: true,
+            strip_dots: true,
+        };
+        assert_eq!(
+            normalization.normalize("A.Lice+alerts@Example.COM"),
+            "alice@example.com"
+        );
+        assert_eq!(
+            normalization.normalize("alice@example.com"),
+            "alice@example.com"
+        );
+    }
+
+    #[test]
+    fn normalization_keeps_dots_in_the_domain() {
+        let normalization = SenderNormalization {
+            strip_dots: true,
+            ..SenderNormalization::default()
+        };
+        assert_eq!(
+            normalization.normalize("a.b@mail.example.com"),
+            "ab@mail.example.com"
+        );
+    }
+
+    #[test]
+    fn every_discord_host_is_accepted() {
+        for host in DISCORD_HOSTS.iter() {
+            let url = format!("https://{}/api/webhooks/1/token", host);
+            let auth = DiscordWebhookAuth::from_url(&url).expect("accepted host");
+            assert_eq!((auth.id, auth.token.as_str()), (1, "token"));
+        }
+    }
+
+    #[test]
+    fn other_hosts_are_rejected() {
+        match DiscordWebhookAuth::from_url("https://evil.com/api/webhooks/1/token") {
+            Err(DiscordWebhookAuthUrlError::UnexpectedHost(host)) => assert_eq!(host, "evil.com"),
+            result => panic!("unexpected result {:?}", result.map(|auth| auth.id)),
+        }
+    }
+
+    #[test]
+    fn url_round_trips_through_every_host() {
+        for host in DISCORD_HOSTS.iter() {
+            let url = format!("https://{}/api/webhooks/1/token?thread_id=2", host);
+            let auth = DiscordWebhookAuth::from_url(&url).expect("accepted host");
+            assert_eq!(auth.to_url(), url);
+        }
+        let auth = DiscordWebhookAuth::new(1, "token".into());
+        assert_eq!(auth.to_url(), "https://discord.com/api/webhooks/1/token");
+    }
+
+    #[test]
+    fn multipart_body_holds_the_payload_and_the_file() {
+        let attachment =
+            Attachment::new("message.eml", "message/rfc822", b"Subject: hi\r\n".to_vec());
+        let (body, boundary) = attachment.multipart_body("{}");
+        let body = String::from_utf8(body).expect("utf-8 body");
+        assert_eq!(
+            body,
+            format!(
+                "--{0}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\
+                 Content-Type: application/json\r\n\r\n{{}}\r\n\
+                 --{0}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"message.eml\"\r\n\
+                 Content-Type: message/rfc822\r\n\r\nSubject: hi\r\n\r\n--{0}--\r\n",
+                boundary
+            )
+        );
+    }
+
+    #[test]
+    fn multipart_boundary_avoids_the_file() {
+        let attachment = Attachment::new(
+            "body.txt",
+            "text/plain",
+            b"--smtp-discord-bridge-boundary--".to_vec(),
+        );
+        let (_, boundary) = attachment.multipart_body("{}");
+        assert!(!contains(&attachment.data, boundary.as_bytes()));
+    }
+
+    #[test]
+    fn insecure_url_must_still_be_on_discord() {
+        let auth =
+            DiscordWebhookAuth::from_url_allow_insecure("http://discord.com/api/webhooks/1/token")
+                .expect("http is allowed");
+        assert_eq!(auth.token, "token");
+        match DiscordWebhookAuth::from_url_allow_insecure(
+            "http://localhost:8080/api/webhooks/1/token",
+        ) {
+            Err(DiscordWebhookAuthUrlError::UnexpectedHost(host)) => assert_eq!(host, "localhost"),
+            other => panic!("expected an unexpected host error, got {:?}", other),
+        }
+    }
 }
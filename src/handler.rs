@@ -0,0 +1,1803 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::annotate::AnnotationLookup;
+use crate::discord::Attachment;
+use crate::headers::Headers;
+use crate::MailToDiscord;
+use log::{debug, warn};
+use samotop::model::command::{SmtpMail, SmtpPath};
+use samotop::model::mail::Envelope;
+use serde::Deserialize;
+use serde_json::json;
+use serenity::builder::ExecuteWebhook;
+use serenity::model::channel::Embed;
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::{form_urlencoded, Url};
+
+/// Discord's limit on the number of fields in an embed
+pub const EMBED_MAX_FIELDS: usize = 25;
+/// Discord's limit on the length of an embed field name
+pub const EMBED_FIELD_NAME_MAX: usize = 256;
+/// Discord's limit on the length of an embed field value
+pub const EMBED_FIELD_VALUE_MAX: usize = 1024;
+/// Discord's limit on the length of an embed author name
+pub const EMBED_AUTHOR_NAME_MAX: usize = 256;
+/// Discord's limit on the length of an embed title
+pub const EMBED_TITLE_MAX: usize = 256;
+/// Discord's limit on the length of message content
+pub const MESSAGE_CONTENT_MAX: usize = 2000;
+/// Title used when a mail has no subject
+pub const DEFAULT_TITLE: &str = "New Message";
+/// Discord's limit on the length of an embed footer
+pub const EMBED_FOOTER_TEXT_MAX: usize = 2048;
+/// Discord's limit on the length of a webhook username
+pub const WEBHOOK_USERNAME_MAX: usize = 80;
+/// Words Discord refuses in a webhook username, matched case insensitively
+pub const WEBHOOK_USERNAME_FORBIDDEN: [&str; 2] = ["discord", "clyde"];
+/// Discord's limit on the length of an embed description
+pub const EMBED_DESCRIPTION_MAX: usize = 4096;
+/// Maximum number of embeds in a webhook message
+pub const MESSAGE_MAX_EMBEDS: usize = 10;
+
+/// How the mail body is laid out in the embed
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyLayout {
+    /// The whole body goes in one field
+    #[default]
+    Single,
+    /// Long bodies are split into one field per section
+    Sections,
+    /// The body goes in the embed description, which has a larger limit than a field
+    Description,
+}
+
+/// How a long mail body is split into sections
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SectionSplit {
+    /// Sections are separated by blank lines
+    #[default]
+    BlankLines,
+    /// Sections start at markdown `#` headings or underlined headings
+    Headings,
+}
+
+/// Where the subject is shown
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubjectPlacement {
+    /// The subject is the embed title
+    #[default]
+    Title,
+    /// The subject is the message content, which shows in notifications
+    Content,
+    /// The subject is both the embed title and the message content
+    Both,
+}
+
+/// What the embed title shows when a message has no usable subject
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubjectFallback {
+    /// The default title is shown
+    #[default]
+    DefaultTitle,
+    /// The first non-blank line of the body is shown
+    FirstLine,
+}
+
+/// Where an embed field's value comes from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldSource {
+    /// The sender
+    From,
+    /// The Sender header, when it names someone other than the From header
+    Sender,
+    /// The envelope recipients
+    To,
+    /// The Bcc header, which is only kept when Bcc headers are shown
+    Bcc,
+    /// The Subject header
+    Subject,
+    /// The Date header
+    Date,
+    /// The message size
+    Size,
+    /// The Content-Language header
+    Language,
+    /// The message body
+    Body,
+}
+
+impl FieldSource {
+    /// Returns the label used when none is configured
+    pub fn default_label(self) -> &'static str {
+        match self {
+            FieldSource::From => "From",
+            FieldSource::Sender => "Sender",
+            FieldSource::To => "To",
+            FieldSource::Bcc => "Bcc",
+            FieldSource::Subject => "Subject",
+            FieldSource::Date => "Date",
+            FieldSource::Size => "Size",
+            FieldSource::Language => "Language",
+            FieldSource::Body => "Body",
+        }
+    }
+}
+
+/// An embed field, in the order it is shown
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FieldSpec {
+    /// Where the field's value comes from
+    pub source: FieldSource,
+    /// Label shown for the field, defaulting to the source's own name
+    pub label: Option<String>,
+    /// Markdown applied to the field's value
+    #[serde(default)]
+    pub style: FieldStyle,
+}
+
+/// Markdown applied to an embed field's value
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldStyle {
+    /// The value is shown as is
+    #[default]
+    Plain,
+    /// The value is shown as inline code, which suits IDs, IPs and addresses
+    Code,
+    /// The value is shown in bold
+    Bold,
+}
+
+impl FieldSpec {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `source` - where the field's value comes from
+    pub fn new(source: FieldSource) -> Self {
+        Self {
+            source,
+            label: None,
+            style: FieldStyle::default(),
+        }
+    }
+
+    /// Returns the label shown for the field
+    pub fn label(&self) -> &str {
+        self.label
+            .as_deref()
+            .unwrap_or_else(|| self.source.default_label())
+    }
+}
+
+/// What happens to a Bcc header left in a message
+///
+/// Bcc is normally stripped before a message is sent, but some locally generated mail keeps it,
+/// and showing it would reveal the hidden recipients.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BccHandling {
+    /// The header is dropped before the message is formatted
+    #[default]
+    Redact,
+    /// The header is kept and shown in a Bcc field
+    Show,
+}
+
+/// How the webhook username is chosen for each message
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum WebhookUsername {
+    /// The webhook's own name is used
+    #[default]
+    Default,
+    /// The same name is used for every message
+    Static(String),
+    /// The sender's display name is used, or their address if they have none
+    Sender,
+}
+
+/// Where the embed author links to
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthorLink {
+    /// A `mailto:` link for the sender
+    Mailto,
+    /// A URL template with `{address}` replaced by the sender's address
+    Template(String),
+}
+
+impl AuthorLink {
+    /// Placeholder in a URL template that is replaced by the sender's address
+    pub const ADDRESS_PLACEHOLDER: &'static str = "{address}";
+
+    /// Parses an author link setting, checking that templates produce valid URLs
+    ///
+    /// # Parameters
+    /// * `link` - either `mailto` or a URL template containing `{address}`
+    pub fn parse(link: &str) -> Result<Self, AuthorLinkError> {
+        if link == "mailto" {
+            return Ok(AuthorLink::Mailto);
+        }
+        if !link.contains(Self::ADDRESS_PLACEHOLDER) {
+            return Err(AuthorLinkError::MissingPlaceholder);
+        }
+        Url::parse(&link.replace(Self::ADDRESS_PLACEHOLDER, "user%40example.com"))
+            .map_err(AuthorLinkError::InvalidUrl)?;
+        Ok(AuthorLink::Template(link.into()))
+    }
+
+    /// Builds the link for a sender, if the address makes a valid URL
+    ///
+    /// # Parameters
+    /// * `address` - the sender's bare address
+    fn url(&self, address: &str) -> Option<String> {
+        let url = match self {
+            AuthorLink::Mailto => Url::parse(&format!("mailto:{}", address)),
+            AuthorLink::Template(template) => {
+                let encoded =
+                    form_urlencoded::byte_serialize(address.as_bytes()).collect::<String>();
+                Url::parse(&template.replace(Self::ADDRESS_PLACEHOLDER, &encoded))
+            }
+        };
+        url.ok().map(|url| url.into_string())
+    }
+}
+
+/// Errors from parsing an author link setting
+#[derive(Debug)]
+pub enum AuthorLinkError {
+    /// The template has no `{address}` placeholder
+    MissingPlaceholder,
+    /// The template doesn't produce a valid URL
+    InvalidUrl(url::ParseError),
+}
+
+/// Sender shown when a mail has neither a From header nor an envelope sender
+pub const DEFAULT_UNKNOWN_SENDER: &str = "unknown sender";
+
+/// Default handler that formats mail as a single embed
+#[derive(Clone, Default)]
+pub struct EmbedHandler {
+    /// How the mail body is laid out in the embed
+    body_layout: BodyLayout,
+    /// How a long mail body is split into sections
+    section_split: SectionSplit,
+    /// Whether the From field is hidden behind a spoiler
+    spoiler_from: bool,
+    /// Whether the To field is hidden behind a spoiler
+    spoiler_to: bool,
+    /// Whether to show the message size
+    show_size: bool,
+    /// Whether to show the declared content language
+    show_language: bool,
+    /// Whether to show the Sender header when it differs from the From header
+    show_sender: bool,
+    /// What to show as the sender when neither a From header nor an envelope sender is present
+    unknown_sender: Option<String>,
+    /// Where the embed author links to, if the sender is shown as the author
+    author_link: Option<AuthorLink>,
+    /// How the webhook username is chosen
+    username: WebhookUsername,
+    /// Which fields are shown and in what order, if not the default
+    fields: Option<Vec<FieldSpec>>,
+    /// Whether the first and last hops of the Received trace go in the footer
+    show_received_trace: bool,
+    /// Where the subject is shown
+    subject_placement: SubjectPlacement,
+    /// What the title shows when the subject is missing or blank
+    subject_fallback: SubjectFallback,
+    /// Whether URLs in the body are wrapped in angle brackets so Discord doesn't preview them
+    suppress_link_previews: bool,
+    /// Whether table-like regions of the body are put in code blocks to keep them aligned
+    fence_tables: bool,
+    /// Whether a body too long for one embed is continued rather than cut short
+    split_long_bodies: bool,
+    /// How many lines of a longer body are shown, with the whole body attached, if it is cut
+    body_preview_lines: Option<usize>,
+    /// Color of the embeds, if not Discord's default
+    color: Option<u32>,
+    /// Extra fields added for known senders
+    annotations: Option<Arc<AnnotationLookup>>,
+    /// What happens to a Bcc header left in a message
+    bcc: BccHandling,
+}
+
+impl EmbedHandler {
+    /// Constructor
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how the mail body is laid out in the embed
+    ///
+    /// # Parameters
+    /// * `body_layout` - the body layout
+    pub fn with_body_layout(mut self, body_layout: BodyLayout) -> Self {
+        self.body_layout = body_layout;
+        self
+    }
+
+    /// Sets how a long mail body is split into sections
+    ///
+    /// # Parameters
+    /// * `section_split` - the splitting heuristic
+    pub fn with_section_split(mut self, section_split: SectionSplit) -> Self {
+        self.section_split = section_split;
+        self
+    }
+
+    /// Sets which address fields are hidden behind spoilers
+    ///
+    /// # Parameters
+    /// * `from` - whether the From field is hidden
+    /// * `to` - whether the To field is hidden
+    pub fn with_spoilers(mut self, from: bool, to: bool) -> Self {
+        self.spoiler_from = from;
+        self.spoiler_to = to;
+        self
+    }
+
+    /// Sets whether the message size is shown
+    ///
+    /// # Parameters
+    /// * `show_size` - whether to add a size field
+    pub fn with_size(mut self, show_size: bool) -> Self {
+        self.show_size = show_size;
+        self
+    }
+
+    /// Sets whether the declared content language is shown, when the mail has one
+    ///
+    /// # Parameters
+    /// * `show_language` - whether to add a language field
+    pub fn with_language(mut self, show_language: bool) -> Self {
+        self.show_language = show_language;
+        self
+    }
+
+    /// Sets what is shown as the sender when neither a From header nor an envelope sender exists
+    ///
+    /// # Parameters
+    /// * `unknown_sender` - the placeholder sender
+    pub fn with_unknown_sender(mut self, unknown_sender: &str) -> Self {
+        self.unknown_sender = Some(unknown_sender.into());
+        self
+    }
+
+    /// Shows the sender as the embed author, linking to them
+    ///
+    /// The author is left off when the From field is hidden behind a spoiler.
+    ///
+    /// # Parameters
+    /// * `author_link` - where the author links to
+    pub fn with_author_link(mut self, author_link: AuthorLink) -> Self {
+        self.author_link = Some(author_link);
+        self
+    }
+
+    /// Sets how the webhook username is chosen for each message
+    ///
+    /// # Parameters
+    /// * `username` - the username mode
+    pub fn with_username(mut self, username: WebhookUsername) -> Self {
+        self.username = username;
+        self
+    }
+
+    /// Sets which fields are shown, in order, and what they are called
+    ///
+    /// This replaces the default From, To, Size, Language and Body fields, so the size and
+    /// language toggles have no effect once a field list is given.
+    ///
+    /// # Parameters
+    /// * `fields` - the fields to show
+    pub fn with_fields(mut self, fields: Vec<FieldSpec>) -> Self {
+        self.fields = Some(fields);
+        self
+    }
+
+    /// Sets whether a condensed Received trace is shown in the footer
+    ///
+    /// # Parameters
+    /// * `show_received_trace` - whether to show the first and last hops
+    pub fn with_received_trace(mut self, show_received_trace: bool) -> Self {
+        self.show_received_trace = show_received_trace;
+        self
+    }
+
+    /// Sets where the subject is shown
+    ///
+    /// # Parameters
+    /// * `subject_placement` - the embed title, the message content, or both
+    pub fn with_subject_placement(mut self, subject_placement: SubjectPlacement) -> Self {
+        self.subject_placement = subject_placement;
+        self
+    }
+
+    /// Sets what the title shows when the subject is missing or blank
+    ///
+    /// # Parameters
+    /// * `subject_fallback` - the title fallback
+    pub fn with_subject_fallback(mut self, subject_fallback: SubjectFallback) -> Self {
+        self.subject_fallback = subject_fallback;
+        self
+    }
+
+    /// Sets whether URLs in the body are wrapped so Discord doesn't show link previews
+    ///
+    /// # Parameters
+    /// * `suppress_link_previews` - whether to wrap URLs in angle brackets
+    pub fn with_link_previews_suppressed(mut self, suppress_link_previews: bool) -> Self {
+        self.suppress_link_previews = suppress_link_previews;
+        self
+    }
+
+    /// Sets whether table-like regions of the body are put in code blocks
+    ///
+    /// # Parameters
+    /// * `fence_tables` - whether to wrap aligned tables in code blocks
+    pub fn with_tables_fenced(mut self, fence_tables: bool) -> Self {
+        self.fence_tables = fence_tables;
+        self
+    }
+
+    /// Sets whether a body too long for one embed is continued rather than cut short
+    ///
+    /// The fields or description chunks past the first embed are left for the sender to post as
+    /// continuation messages.
+    ///
+    /// # Parameters
+    /// * `split_long_bodies` - whether to keep the whole body
+    pub fn with_long_bodies_split(mut self, split_long_bodies: bool) -> Self {
+        self.split_long_bodies = split_long_bodies;
+        self
+    }
+
+    /// Sets the color of the embeds
+    ///
+    /// # Parameters
+    /// * `color` - the embed color as 0xRRGGBB
+    pub fn with_color(mut self, color: u32) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    /// Shows only the first lines of a longer body, attaching the whole body as `body.txt`
+    ///
+    /// # Parameters
+    /// * `body_preview_lines` - how many lines of the body are shown
+    pub fn with_body_preview_lines(mut self, body_preview_lines: usize) -> Self {
+        self.body_preview_lines = Some(body_preview_lines);
+        self
+    }
+
+    /// Adds extra fields for senders found in a lookup
+    ///
+    /// # Parameters
+    /// * `annotations` - the sender lookup
+    pub fn with_annotations(mut self, annotations: Arc<AnnotationLookup>) -> Self {
+        self.annotations = Some(annotations);
+        self
+    }
+
+    /// Sets whether the Sender header is shown when it differs from the From header
+    ///
+    /// # Parameters
+    /// * `show_sender` - whether to add a Sender field for mail sent on someone's behalf
+    pub fn with_sender(mut self, show_sender: bool) -> Self {
+        self.show_sender = show_sender;
+        self
+    }
+
+    /// Sets what happens to a Bcc header left in a message
+    ///
+    /// # Parameters
+    /// * `bcc` - how Bcc headers are handled
+    pub fn with_bcc(mut self, bcc: BccHandling) -> Self {
+        self.bcc = bcc;
+        self
+    }
+
+    /// Returns the fields to show, in order
+    fn field_specs(&self) -> Vec<FieldSpec> {
+        if let Some(fields) = &self.fields {
+            return fields.clone();
+        }
+        let mut fields = vec![FieldSpec::new(FieldSource::From)];
+        if self.show_sender {
+            fields.push(FieldSpec::new(FieldSource::Sender));
+        }
+        fields.push(FieldSpec::new(FieldSource::To));
+        if self.bcc == BccHandling::Show {
+            fields.push(FieldSpec::new(FieldSource::Bcc));
+        }
+        if self.show_size {
+            fields.push(FieldSpec::new(FieldSource::Size));
+        }
+        if self.show_language {
+            fields.push(FieldSpec::new(FieldSource::Language));
+        }
+        fields.push(FieldSpec::new(FieldSource::Body));
+        fields
+    }
+
+    /// Works out who a mail is from
+    ///
+    /// Prefers the From header, then the envelope sender, then the unknown sender placeholder.
+    ///
+    /// # Parameters
+    /// * `headers` - the message's headers
+    /// * `envelope` - the message's envelope
+    fn sender(&self, headers: &Headers, envelope: &Envelope) -> String {
+        mail_sender(headers, envelope, self.unknown_sender())
+    }
+
+    /// Returns the placeholder shown for mail with no sender
+    fn unknown_sender(&self) -> &str {
+        self.unknown_sender
+            .as_deref()
+            .unwrap_or(DEFAULT_UNKNOWN_SENDER)
+    }
+
+    /// Lays the body out as embed fields
+    ///
+    /// # Parameters
+    /// * `body` - the decoded mail body
+    /// * `body_label` - label for the body, and for sections that have no heading
+    /// * `max_fields` - how many fields are left for the body
+    fn body_fields(
+        &self,
+        body: &str,
+        body_label: &str,
+        max_fields: usize,
+    ) -> Vec<(String, String)> {
+        let single = || {
+            vec![(
+                truncate(body_label, EMBED_FIELD_NAME_MAX),
+                truncate(body, EMBED_FIELD_VALUE_MAX),
+            )]
+        };
+        // Short bodies read fine as a single field
+        if body.chars().count() <= EMBED_FIELD_VALUE_MAX {
+            return single();
+        }
+        if self.body_layout == BodyLayout::Single {
+            if !self.split_long_bodies {
+                return single();
+            }
+            // Continuation messages make room for the rest of the body in further fields
+            let continued_label = format!("{} (cont.)", body_label);
+            return split_body(body, EMBED_FIELD_VALUE_MAX)
+                .into_iter()
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let label = if i == 0 { body_label } else { &continued_label };
+                    (truncate(label, EMBED_FIELD_NAME_MAX), chunk)
+                })
+                .collect();
+        }
+        let sections = split_sections(body, self.section_split);
+        // Continuation messages make room for every field
+        let max_fields = if self.split_long_bodies {
+            usize::MAX
+        } else {
+            max_fields
+        };
+        if max_fields == 0 {
+            return single();
+        }
+        // Spread sections too long for one field over several, marking the continuations
+        let mut sections: Vec<(Option<String>, String, bool)> = sections
+            .into_iter()
+            .flat_map(|(label, content)| {
+                split_body(&content, EMBED_FIELD_VALUE_MAX)
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, chunk)| (label.clone(), chunk, i > 0))
+            })
+            .collect();
+        if sections.len() < 2 {
+            return single();
+        }
+        // Fold any sections past the field limit into the last field
+        if sections.len() > max_fields {
+            let overflow = sections.split_off(max_fields);
+            let last = sections.last_mut().expect("sections is not empty");
+            for (label, content, continued) in overflow {
+                if continued {
+                    last.1.push('\n');
+                } else {
+                    last.1.push_str("\n\n");
+                    if let Some(label) = label {
+                        last.1.push_str(&label);
+                        last.1.push('\n');
+                    }
+                }
+                last.1.push_str(&content);
+            }
+        }
+        sections
+            .into_iter()
+            .map(|(label, content, continued)| {
+                let label = label.unwrap_or_else(|| body_label.into());
+                let label = if continued {
+                    format!("{} (cont.)", label)
+                } else {
+                    label
+                };
+                (
+                    truncate(&label, EMBED_FIELD_NAME_MAX),
+                    truncate(&content, EMBED_FIELD_VALUE_MAX),
+                )
+            })
+            .collect()
+    }
+}
+
+impl MailToDiscord for EmbedHandler {
+    fn handle(&mut self, envelope: &Envelope, body: &[u8], webhook_builder: &mut ExecuteWebhook) {
+        let (mut headers, body_start) = Headers::parse(body);
+        if headers.get("Bcc").is_some() {
+            match self.bcc {
+                BccHandling::Redact => {
+                    let removed = headers.remove("Bcc");
+                    debug!("Redacted {} Bcc header(s)", removed);
+                }
+                BccHandling::Show => debug!("Showing Bcc header"),
+            }
+        }
+        let sender = self.sender(&headers, envelope);
+        let rcpts = envelope
+            .rcpts
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let size = body.len();
+        let body = String::from_utf8_lossy(&body[body_start..]);
+        // The whole of a longer body is attached, so only its first lines are shown
+        let body = match self
+            .body_preview_lines
+            .and_then(|lines| body_preview(&body, lines))
+        {
+            Some(preview) => preview.into(),
+            None => body,
+        };
+        let body = if self.fence_tables {
+            fence_tables(&body).into()
+        } else {
+            body
+        };
+        let body = if self.suppress_link_previews {
+            wrap_urls(&body)
+        } else {
+            body.into_owned()
+        };
+        let specs = self.field_specs();
+        // Work out every field's value up front, leaving the body until we know how much room it has
+        let mut fields: Vec<(String, Option<String>, bool)> = Vec::new();
+        for spec in &specs {
+            let value = match spec.source {
+                FieldSource::From => Some(sender.clone()),
+                FieldSource::Sender => delegated_sender(&headers).map(String::from),
+                FieldSource::To => Some(rcpts.clone()),
+                FieldSource::Bcc => Some(headers.get_all("Bcc").collect::<Vec<_>>().join(", "))
+                    .filter(|bcc| !bcc.is_empty()),
+                FieldSource::Subject => headers.get("Subject").map(String::from),
+                FieldSource::Date => headers.get("Date").map(String::from),
+                FieldSource::Size => Some(format_size(size)),
+                FieldSource::Language => Some(headers.content_language().join(", "))
+                    .filter(|languages| !languages.is_empty()),
+                FieldSource::Body => None,
+            };
+            let spoiler = match spec.source {
+                FieldSource::From | FieldSource::Sender => self.spoiler_from,
+                FieldSource::To => self.spoiler_to,
+                _ => false,
+            };
+            let value = value.map(|value| field_value(&styled(&value, spec.style), spoiler));
+            match (spec.source, value) {
+                (FieldSource::Body, _) => fields.push((spec.label().into(), None, false)),
+                // Headers the message doesn't have are left out
+                (_, None) => (),
+                (_, Some(value)) => fields.push((spec.label().into(), Some(value), true)),
+            }
+        }
+        // Add context for known senders ahead of the body
+        if let Some(annotations) = &self.annotations {
+            let body_index = fields
+                .iter()
+                .position(|(_, value, _)| value.is_none())
+                .unwrap_or(fields.len());
+            let extra = annotations
+                .annotations(bare_address(&sender))
+                .into_iter()
+                .map(|(label, value)| (label, Some(field_value(&value, false)), true));
+            fields.splice(body_index..body_index, extra);
+        }
+        let other_fields = fields
+            .iter()
+            .filter(|(_, value, _)| value.is_some())
+            .count();
+        // A blank subject would make an empty title, so treat it as missing
+        let subject = headers
+            .get("Subject")
+            .map(str::trim)
+            .filter(|subject| !subject.is_empty());
+        let trace = if self.show_received_trace {
+            received_trace(&headers)
+        } else {
+            None
+        };
+        // Link to the sender unless they are meant to be hidden
+        let author_url = match &self.author_link {
+            Some(author_link) if !self.spoiler_from => author_link.url(bare_address(&sender)),
+            _ => None,
+        };
+        let title = match (subject, self.subject_placement) {
+            (Some(subject), SubjectPlacement::Title) | (Some(subject), SubjectPlacement::Both) => {
+                subject
+            }
+            (None, _) if self.subject_fallback == SubjectFallback::FirstLine => body
+                .lines()
+                .map(str::trim)
+                // Skip code block markers added around tables
+                .find(|line| !line.is_empty() && !line.starts_with("```"))
+                .unwrap_or(DEFAULT_TITLE),
+            _ => DEFAULT_TITLE,
+        };
+        // A description too long for one embed carries on in continuation embeds
+        let mut descriptions = if self.split_long_bodies {
+            split_body(&body, EMBED_DESCRIPTION_MAX)
+        } else {
+            vec![truncate(&body, EMBED_DESCRIPTION_MAX)]
+        }
+        .into_iter();
+        let embed = Embed::fake(|e| {
+            if let Some(author_url) = author_url {
+                e.author(|a| {
+                    a.name(truncate(&sender, EMBED_AUTHOR_NAME_MAX))
+                        .url(author_url)
+                });
+            }
+            e.title(truncate(title, EMBED_TITLE_MAX));
+            if let Some(color) = self.color {
+                e.colour(color);
+            }
+            if let Some(trace) = trace {
+                e.footer(|f| f.text(truncate(&trace, EMBED_FOOTER_TEXT_MAX)));
+            }
+            for (label, value, inline) in fields {
+                match value {
+                    Some(value) => {
+                        e.field(truncate(&label, EMBED_FIELD_NAME_MAX), value, inline);
+                    }
+                    None if self.body_layout == BodyLayout::Description => {
+                        if let Some(description) = descriptions.next() {
+                            e.description(description);
+                        }
+                    }
+                    None => {
+                        let max_fields = EMBED_MAX_FIELDS.saturating_sub(other_fields);
+                        for (label, content) in self.body_fields(&body, &label, max_fields) {
+                            e.field(label, content, false);
+                        }
+                    }
+                }
+            }
+            e
+        });
+        let mut embeds = vec![embed];
+        if self.body_layout == BodyLayout::Description {
+            embeds.extend(descriptions.map(|description| {
+                let mut embed = json!({
+                    "title": continued_title(title),
+                    "description": description,
+                });
+                if let Some(color) = self.color {
+                    embed["color"] = json!(color);
+                }
+                embed
+            }));
+        }
+        webhook_builder.embeds(embeds);
+        if let Some(subject) = subject {
+            if self.subject_placement != SubjectPlacement::Title {
+                webhook_builder.content(truncate(subject, MESSAGE_CONTENT_MAX));
+            }
+        }
+        let username = match &self.username {
+            WebhookUsername::Default => None,
+            WebhookUsername::Static(username) => Some(username.clone()),
+            WebhookUsername::Sender => Some(truncate(display_name(&sender), WEBHOOK_USERNAME_MAX)),
+        };
+        // Discord rejects the whole message over a bad username, so leave the webhook's own name
+        match username {
+            Some(username) if username_allowed(&username) => {
+                webhook_builder.username(username);
+            }
+            Some(username) => debug!("Using the webhook's name in place of {:?}", username),
+            None => (),
+        }
+    }
+
+    fn summarize(
+        &mut self,
+        envelope: &Envelope,
+        body: &[u8],
+        webhook_builder: &mut ExecuteWebhook,
+    ) {
+        attachment_summary(envelope, body, self.unknown_sender(), webhook_builder);
+    }
+
+    fn body_attachment(&mut self, _: &Envelope, body: &[u8]) -> Option<Attachment> {
+        let lines = self.body_preview_lines?;
+        let (_, body_start) = Headers::parse(body);
+        let text = String::from_utf8_lossy(&body[body_start..]);
+        body_preview(&text, lines)?;
+        Some(Attachment::new(
+            "body.txt",
+            "text/plain; charset=utf-8",
+            text.into_owned().into_bytes(),
+        ))
+    }
+}
+
+/// Works out who a mail is from
+///
+/// Prefers the From header, then the envelope sender, then the unknown sender placeholder.
+///
+/// # Parameters
+/// * `headers` - the message's headers
+/// * `envelope` - the message's envelope
+/// * `unknown_sender` - the placeholder used when neither names a sender
+pub fn mail_sender(headers: &Headers, envelope: &Envelope, unknown_sender: &str) -> String {
+    let from_header = headers.get("From").filter(|from| !from.is_empty());
+    // The null reverse path is used for bounces, and doesn't name anyone
+    let envelope_sender = envelope
+        .mail
+        .as_ref()
+        .map(SmtpMail::from)
+        .filter(|path| **path != SmtpPath::Null);
+    match (from_header, envelope_sender) {
+        (Some(from), _) => from.into(),
+        (None, Some(path)) => path.to_string(),
+        (None, None) => unknown_sender.into(),
+    }
+}
+
+/// Cuts a body down to its first lines, noting how many were left out
+///
+/// Returns `None` when the body has no more lines than that.
+///
+/// # Parameters
+/// * `body` - the decoded mail body
+/// * `lines` - how many lines are kept
+fn body_preview(body: &str, lines: usize) -> Option<String> {
+    let total = body.lines().count();
+    if total <= lines {
+        return None;
+    }
+    let mut preview = body.lines().take(lines).collect::<Vec<_>>().join("\n");
+    preview.push_str(&format!("\n… {} more lines not shown", total - lines));
+    Some(preview)
+}
+
+/// Builds a short summary of a mail whose raw message is attached instead of shown
+///
+/// The summary has the sender, recipients, subject and size, leaving the body to the attachment.
+///
+/// # Parameters
+/// * `envelope` - the mail's envelope
+/// * `body` - the raw mail
+/// * `unknown_sender` - the placeholder shown for mail with no sender
+/// * `webhook_builder` - the payload to fill in
+pub fn attachment_summary(
+    envelope: &Envelope,
+    body: &[u8],
+    unknown_sender: &str,
+    webhook_builder: &mut ExecuteWebhook,
+) {
+    let (headers, _) = Headers::parse(body);
+    let sender = mail_sender(&headers, envelope, unknown_sender);
+    let rcpts = envelope
+        .rcpts
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let title = headers
+        .get("Subject")
+        .map(str::trim)
+        .filter(|subject| !subject.is_empty())
+        .unwrap_or(DEFAULT_TITLE);
+    webhook_builder.embeds(vec![json!({
+        "title": truncate(title, EMBED_TITLE_MAX),
+        "description": "The original message is attached.",
+        "fields": [
+            {
+                "name": "From",
+                "value": truncate(&sender, EMBED_FIELD_VALUE_MAX),
+                "inline": true,
+            },
+            {
+                "name": "To",
+                "value": truncate(&rcpts, EMBED_FIELD_VALUE_MAX),
+                "inline": true,
+            },
+            {
+                "name": "Size",
+                "value": format_size(body.len()),
+                "inline": true,
+            },
+        ],
+    })]);
+}
+
+/// Returns the Sender header if it names a different mailbox than the From header
+///
+/// # Parameters
+/// * `headers` - the message's headers
+fn delegated_sender(headers: &Headers) -> Option<&str> {
+    let sender = headers.get("Sender").filter(|sender| !sender.is_empty())?;
+    match headers.get("From") {
+        Some(from) if bare_address(from).eq_ignore_ascii_case(bare_address(sender)) => None,
+        _ => Some(sender),
+    }
+}
+
+/// Pulls the bare address out of a sender such as `Name <user@example.com>`
+///
+/// # Parameters
+/// * `sender` - the sender as shown in the From field
+fn bare_address(sender: &str) -> &str {
+    match (sender.rfind('<'), sender.rfind('>')) {
+        (Some(start), Some(end)) if start < end => sender[start + 1..end].trim(),
+        _ => sender.trim(),
+    }
+}
+
+/// Condenses the Received trace to its first and last hops
+///
+/// # Parameters
+/// * `headers` - the message's headers
+fn received_trace(headers: &Headers) -> Option<String> {
+    let hops = headers.received_hops();
+    match hops.as_slice() {
+        [] => None,
+        [only] => Some(format!("Received: {}", only)),
+        [first, .., last] => Some(format!(
+            "First hop: {} · Last hop: {} ({} hops)",
+            first,
+            last,
+            hops.len()
+        )),
+    }
+}
+
+/// Pulls the display name out of a sender such as `Name <user@example.com>`
+///
+/// Falls back to the bare address when the sender has no display name.
+///
+/// # Parameters
+/// * `sender` - the sender as shown in the From field
+fn display_name(sender: &str) -> &str {
+    let name = match sender.rfind('<') {
+        Some(start) => sender[..start].trim().trim_matches('"').trim(),
+        None => "",
+    };
+    if name.is_empty() {
+        bare_address(sender)
+    } else {
+        name
+    }
+}
+
+/// Checks whether Discord accepts a webhook username
+///
+/// # Parameters
+/// * `username` - the username
+pub fn username_allowed(username: &str) -> bool {
+    let lowercase = username.to_lowercase();
+    !username.trim().is_empty()
+        && username.chars().count() <= WEBHOOK_USERNAME_MAX
+        && !WEBHOOK_USERNAME_FORBIDDEN
+            .iter()
+            .any(|word| lowercase.contains(word))
+}
+
+/// Formats a duration for humans
+///
+/// # Parameters
+/// * `duration` - the duration
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs < 1.0 {
+        format!("{} ms", duration.as_millis())
+    } else if secs < 60.0 {
+        format!("{:.1} s", secs)
+    } else {
+        format!("{}m {}s", duration.as_secs() / 60, duration.as_secs() % 60)
+    }
+}
+
+/// Formats a byte count for humans
+///
+/// # Parameters
+/// * `bytes` - the number of bytes
+pub fn format_size(bytes: usize) -> String {
+    const KIB: f64 = 1024.0;
+    const MIB: f64 = KIB * 1024.0;
+    let bytes_f = bytes as f64;
+    if bytes_f >= MIB {
+        format!("{:.1} MB", bytes_f / MIB)
+    } else if bytes_f >= KIB {
+        format!("{:.1} KB", bytes_f / KIB)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// Applies markdown to a field value, escaping it first so it can't break the markup
+///
+/// The value is shortened first so truncation never cuts off the closing markup.
+///
+/// # Parameters
+/// * `value` - the field value
+/// * `style` - the markdown to apply
+fn styled(value: &str, style: FieldStyle) -> String {
+    // Room for the markup and a spoiler around it all
+    const MARKUP_RESERVE: usize = 16;
+    let max_chars = EMBED_FIELD_VALUE_MAX - MARKUP_RESERVE;
+    match style {
+        FieldStyle::Plain => value.into(),
+        FieldStyle::Code => {
+            // Backticks can't be escaped inside inline code, so swap them for a lookalike
+            let value = truncate(value, max_chars).replace('`', "\u{2cb}");
+            format!("`{}`", value)
+        }
+        FieldStyle::Bold => {
+            // Escapes add characters, so stop before an escaped character would overflow
+            let mut escaped = String::with_capacity(value.len());
+            let mut chars = 0;
+            for c in value.chars() {
+                // Pipes are left to the spoiler markup, which escapes them itself
+                let escape = matches!(c, '\\' | '*' | '_' | '~' | '`');
+                let width = if escape { 2 } else { 1 };
+                if chars + width > max_chars {
+                    escaped.push('…');
+                    break;
+                }
+                if escape {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+                chars += width;
+            }
+            format!("**{}**", escaped)
+        }
+    }
+}
+
+/// Fits a value into an embed field, optionally hiding it behind a spoiler
+///
+/// # Parameters
+/// * `value` - the field value
+/// * `spoiler` - whether to wrap the value in spoiler markup
+fn field_value(value: &str, spoiler: bool) -> String {
+    if spoiler {
+        // Escape pipes so the value can't close the spoiler early, and leave room for the markup
+        let escaped = value.replace('|', "\\|");
+        format!("||{}||", truncate(&escaped, EMBED_FIELD_VALUE_MAX - 4))
+    } else {
+        truncate(value, EMBED_FIELD_VALUE_MAX)
+    }
+}
+
+/// Splits a mail body into optionally labeled sections
+///
+/// # Parameters
+/// * `body` - the decoded mail body
+/// * `split` - the splitting heuristic
+fn split_sections(body: &str, split: SectionSplit) -> Vec<(Option<String>, String)> {
+    let mut sections = Vec::new();
+    let mut label = None;
+    let mut content: Vec<&str> = Vec::new();
+    let mut lines = body.lines().peekable();
+    while let Some(line) = lines.next() {
+        // Work out whether this line starts a new section, and what it is called
+        let heading = match split {
+            SectionSplit::BlankLines => {
+                if line.trim().is_empty() {
+                    Some(None)
+                } else {
+                    None
+                }
+            }
+            SectionSplit::Headings => {
+                if line.starts_with('#') {
+                    Some(Some(line.trim_start_matches('#').trim().to_string()))
+                } else if !line.trim().is_empty() && lines.peek().is_some_and(|l| is_underline(l)) {
+                    lines.next();
+                    Some(Some(line.trim().to_string()))
+                } else {
+                    None
+                }
+            }
+        };
+        match heading {
+            Some(next_label) => {
+                push_section(&mut sections, label.take(), &content);
+                content.clear();
+                label = next_label;
+            }
+            None => content.push(line),
+        }
+    }
+    push_section(&mut sections, label, &content);
+    sections
+}
+
+/// Adds a section unless it has neither a label nor content
+///
+/// # Parameters
+/// * `sections` - the sections found so far
+/// * `label` - the section's heading, if any
+/// * `content` - the section's lines
+fn push_section(
+    sections: &mut Vec<(Option<String>, String)>,
+    label: Option<String>,
+    content: &[&str],
+) {
+    let content = content.join("\n").trim().to_string();
+    match (label, content.is_empty()) {
+        (None, true) => (),
+        (Some(label), true) => sections.push((Some(label), "(empty)".into())),
+        (label, false) => sections.push((label, content)),
+    }
+}
+
+/// Checks whether a line underlines the heading above it
+///
+/// # Parameters
+/// * `line` - the line to check
+fn is_underline(line: &str) -> bool {
+    let line = line.trim();
+    line.len() >= 3 && (line.chars().all(|c| c == '=') || line.chars().all(|c| c == '-'))
+}
+
+/// Wraps bare URLs in angle brackets, which stops Discord from previewing them
+///
+/// URLs in code blocks and inline code are left alone, since Discord doesn't link them and the
+/// brackets would show.
+///
+/// # Parameters
+/// * `text` - the text containing URLs
+fn wrap_urls(text: &str) -> String {
+    let mut wrapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(tick) = rest.find('`') {
+        wrapped.push_str(&wrap_plain_urls(&rest[..tick]));
+        let code = &rest[tick..];
+        let delim = if code.starts_with("```") { "```" } else { "`" };
+        // An unclosed delimiter is shown as is, so only it is skipped
+        let end = code[delim.len()..]
+            .find(delim)
+            .map_or(delim.len(), |end| end + 2 * delim.len());
+        wrapped.push_str(&code[..end]);
+        rest = &code[end..];
+    }
+    wrapped.push_str(&wrap_plain_urls(rest));
+    wrapped
+}
+
+/// Wraps bare URLs in text outside code in angle brackets
+///
+/// URLs already in angle brackets are left alone, and trailing punctuation is kept outside
+/// unless it closes a parenthesis opened in the URL.
+///
+/// # Parameters
+/// * `text` - the text containing URLs
+fn wrap_plain_urls(text: &str) -> String {
+    let mut wrapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = ["http://", "https://"]
+        .iter()
+        .filter_map(|scheme| rest.find(scheme))
+        .min()
+    {
+        let (before, url_onwards) = rest.split_at(start);
+        wrapped.push_str(before);
+        let end = url_onwards
+            .find(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '"')
+            .unwrap_or(url_onwards.len());
+        let url = trim_url(&url_onwards[..end]);
+        if before.ends_with('<') {
+            wrapped.push_str(url);
+        } else {
+            wrapped.push('<');
+            wrapped.push_str(url);
+            wrapped.push('>');
+        }
+        rest = &url_onwards[url.len()..];
+    }
+    wrapped.push_str(rest);
+    wrapped
+}
+
+/// Trims sentence punctuation and unbalanced closing parentheses off the end of a URL
+///
+/// # Parameters
+/// * `url` - the URL as found in the text
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        // Sentence punctuation after a URL is almost never part of it
+        url = url.trim_end_matches(&['.', ',', ';', ':', '!', '?'][..]);
+        // A closing parenthesis is part of the URL only if the URL opened one
+        if url.ends_with(')') && url.matches(')').count() > url.matches('(').count() {
+            url = &url[..url.len() - 1];
+        } else {
+            return url;
+        }
+    }
+}
+
+/// Wraps runs of table-like lines in code blocks so their columns stay aligned
+///
+/// A line looks like part of a table if it has box-drawing characters, two or more `|` column
+/// separators, or two or more gaps of several spaces between words. Only runs of at least two
+/// such lines are wrapped, and text already in a code block is left alone.
+///
+/// # Parameters
+/// * `text` - the mail body
+fn fence_tables(text: &str) -> String {
+    let mut fenced = String::with_capacity(text.len());
+    let mut table: Vec<&str> = Vec::new();
+    let mut in_code = false;
+    for line in text.split('\n') {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        } else if !in_code && is_table_line(line) {
+            table.push(line);
+            continue;
+        }
+        push_table(&mut fenced, &mut table);
+        fenced.push_str(line);
+        fenced.push('\n');
+    }
+    push_table(&mut fenced, &mut table);
+    // Splitting added a line ending after the last line
+    fenced.pop();
+    fenced
+}
+
+/// Appends collected table lines, in a code block if there are enough to be a table
+///
+/// # Parameters
+/// * `fenced` - the text being built
+/// * `table` - the collected lines, which are drained
+fn push_table(fenced: &mut String, table: &mut Vec<&str>) {
+    let is_table = table.len() >= 2;
+    if is_table {
+        fenced.push_str("```\n");
+    }
+    for line in table.drain(..) {
+        fenced.push_str(line.trim_end_matches('\r'));
+        fenced.push('\n');
+    }
+    if is_table {
+        fenced.push_str("```\n");
+    }
+}
+
+/// Checks whether a line looks like a row or border of a plain text table
+///
+/// # Parameters
+/// * `line` - the line to check
+fn is_table_line(line: &str) -> bool {
+    let line = line.trim_end();
+    if line.trim().is_empty() {
+        return false;
+    }
+    if line.chars().any(|c| ('\u{2500}'..='\u{257f}').contains(&c)) {
+        return true;
+    }
+    if line.matches('|').count() >= 2 {
+        return true;
+    }
+    // ASCII borders like +-----+-----+
+    let trimmed = line.trim();
+    if trimmed.starts_with('+') && trimmed.chars().all(|c| matches!(c, '+' | '-' | '=' | ' ')) {
+        return true;
+    }
+    // Columns padded with spaces, ignoring indentation
+    let gaps = trimmed
+        .split("   ")
+        .filter(|column| !column.trim().is_empty())
+        .count();
+    gaps >= 3
+}
+
+/// Splits a body into chunks of at most a number of characters
+///
+/// Chunks break at the last whitespace that fits. A run with no whitespace, such as a long URL
+/// or base64 blob, is cut at the limit instead. Cuts always fall between characters, so a
+/// multi-byte character is never split.
+///
+/// # Parameters
+/// * `body` - the text to split
+/// * `chunk` - the maximum number of characters in a chunk
+pub fn split_body(body: &str, chunk: usize) -> Vec<String> {
+    let max_chars = chunk.max(1);
+    let mut chunks = Vec::new();
+    let mut rest = body.trim();
+    while !rest.is_empty() {
+        // Byte offset just past the last character that fits
+        let limit = match rest.char_indices().nth(max_chars) {
+            Some((limit, _)) => limit,
+            None => {
+                chunks.push(rest.to_string());
+                break;
+            }
+        };
+        let cut = match rest[..limit].rfind(char::is_whitespace) {
+            Some(space) if space > 0 => space,
+            _ => limit,
+        };
+        chunks.push(rest[..cut].trim_end().to_string());
+        rest = rest[cut..].trim_start();
+    }
+    chunks
+}
+
+/// Returns the title of an embed continuing another
+///
+/// # Parameters
+/// * `title` - the title of the embed being continued
+pub fn continued_title(title: &str) -> String {
+    let suffix = " (cont.)";
+    let title = title.trim_end_matches(suffix);
+    let max_chars = EMBED_TITLE_MAX - suffix.chars().count();
+    format!("{}{}", truncate(title, max_chars), suffix)
+}
+
+/// Truncates a string to a number of characters, marking the cut with an ellipsis
+///
+/// # Parameters
+/// * `s` - the string to truncate
+/// * `max_chars` - the maximum number of characters in the result
+pub fn truncate(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        s.into()
+    } else {
+        let mut truncated: String = s.chars().take(max_chars.saturating_sub(1)).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+/// Handler that pipes the raw mail through an external command
+///
+/// The command receives the mail body on stdin, and its stdout becomes the message content.
+/// If the command fails or times out, the fallback handler formats the message instead.
+#[derive(Clone)]
+pub struct ExternalFilterHandler<T> {
+    /// Path to the command
+    command: String,
+    /// Arguments passed to the command
+    args: Vec<String>,
+    /// How long the command may run before it is killed
+    timeout: Duration,
+    /// Handler used when the command fails
+    fallback: T,
+}
+
+impl<T> ExternalFilterHandler<T> {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `command` - path to the command
+    /// * `args` - arguments passed to the command
+    /// * `timeout` - how long the command may run before it is killed
+    /// * `fallback` - handler used when the command fails
+    pub fn new(command: &str, args: Vec<String>, timeout: Duration, fallback: T) -> Self {
+        Self {
+            command: command.into(),
+            args,
+            timeout,
+            fallback,
+        }
+    }
+
+    /// Runs the command on a mail body and returns its output
+    ///
+    /// # Parameters
+    /// * `body` - the mail body fed to the command's stdin
+    fn run(&self, body: &[u8]) -> Result<String, ExternalFilterError> {
+        use ExternalFilterError::*;
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(SpawnError)?;
+        // Feed stdin and drain stdout on their own threads so a chatty command can't deadlock us
+        let mut stdin = child.stdin.take().ok_or(MissingPipe)?;
+        let mut stdout = child.stdout.take().ok_or(MissingPipe)?;
+        let input = body.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+        // Wait for the command to exit, killing it if it takes too long
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(IoError)? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                // The command may have exited in the meantime, so errors here are not interesting
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Timeout);
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // A command that exits without reading its input closes the pipe on us, which is fine
+        let _ = writer.join();
+        let output = reader.join().map_err(|_| MissingPipe)?.map_err(IoError)?;
+        if !status.success() {
+            return Err(ExitFailure(status));
+        }
+        String::from_utf8(output).map_err(|_| InvalidUtf8)
+    }
+}
+
+impl<T> MailToDiscord for ExternalFilterHandler<T>
+where
+    T: MailToDiscord,
+{
+    fn handle(&mut self, envelope: &Envelope, body: &[u8], webhook_builder: &mut ExecuteWebhook) {
+        match self.run(body) {
+            Ok(content) => {
+                // Discord rejects the whole message if the content is too long
+                if content.chars().count() > MESSAGE_CONTENT_MAX {
+                    warn!(
+                        "External filter {} output is too long, cutting it to {} characters",
+                        self.command, MESSAGE_CONTENT_MAX
+                    );
+                }
+                webhook_builder.content(truncate(&content, MESSAGE_CONTENT_MAX));
+            }
+            Err(e) => {
+                warn!(
+                    "External filter {} failed, using default format: {:?}",
+                    self.command, e
+                );
+                self.fallback.handle(envelope, body, webhook_builder);
+            }
+        }
+    }
+
+    /// Summaries are short enough to leave to the fallback handler rather than the command
+    fn summarize(
+        &mut self,
+        envelope: &Envelope,
+        body: &[u8],
+        webhook_builder: &mut ExecuteWebhook,
+    ) {
+        self.fallback.summarize(envelope, body, webhook_builder);
+    }
+
+    /// The whole body is attached whether the command or the fallback handler formats the mail
+    fn body_attachment(&mut self, envelope: &Envelope, body: &[u8]) -> Option<Attachment> {
+        self.fallback.body_attachment(envelope, body)
+    }
+}
+
+/// Error running an external filter command
+#[derive(Debug)]
+pub enum ExternalFilterError {
+    /// Failed to start the command
+    SpawnError(io::Error),
+    /// Failed to talk to or wait on the command
+    IoError(io::Error),
+    /// The command's stdin or stdout was not available
+    MissingPipe,
+    /// The command ran longer than the timeout
+    Timeout,
+    /// The command exited unsuccessfully
+    ExitFailure(ExitStatus),
+    /// The command's output was not valid UTF-8
+    InvalidUtf8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use samotop::model::command::{SmtpAddress, SmtpHost};
+    use serde_json::Value;
+
+    #[test]
+    fn long_filter_output_is_truncated() {
+        let script = format!("printf '%0{}d' 0", MESSAGE_CONTENT_MAX * 2);
+        let mut handler = ExternalFilterHandler::new(
+            "sh",
+            vec!["-c".into(), script],
+            Duration::from_secs(10),
+            EmbedHandler::new(),
+        );
+        let mut webhook_builder = ExecuteWebhook::default();
+        handler.handle(&test_envelope(None), &[], &mut webhook_builder);
+        let content = webhook_builder.0["content"].as_str().expect("content");
+        assert_eq!(content.chars().count(), MESSAGE_CONTENT_MAX);
+    }
+
+    /// Runs a handler on a message and returns the payload it built
+    ///
+    /// # Parameters
+    /// * `handler` - the handler to run
+    /// * `envelope` - the message's envelope
+    /// * `message` - the raw message
+    fn render<T: MailToDiscord>(handler: &mut T, envelope: Envelope, message: &str) -> Value {
+        let mut webhook_builder = ExecuteWebhook::default();
+        handler.handle(&envelope, message.as_bytes(), &mut webhook_builder);
+        json!(webhook_builder.0)
+    }
+
+    /// Returns the value of the first embed's field with a name
+    ///
+    /// # Parameters
+    /// * `payload` - the payload built by a handler
+    /// * `name` - the field name
+    fn field<'a>(payload: &'a Value, name: &str) -> Option<&'a str> {
+        payload["embeds"][0]["fields"]
+            .as_array()?
+            .iter()
+            .find(|field| field["name"] == name)
+            .and_then(|field| field["value"].as_str())
+    }
+
+    /// Builds a MAIL FROM command
+    ///
+    /// # Parameters
+    /// * `local` - the sender's local part
+    /// * `domain` - the sender's domain
+    fn test_mail(local: &str, domain: &str) -> SmtpMail {
+        SmtpMail::Mail(SmtpPath::Direct(SmtpAddress::Mailbox(
+            local.into(),
+            SmtpHost::Domain(domain.into()),
+        )))
+    }
+
+    #[test]
+    fn missing_from_header_falls_back_to_envelope_sender() {
+        let mut handler = EmbedHandler::new();
+        let envelope = test_envelope(Some(test_mail("cron", "example.com")));
+        let payload = render(&mut handler, envelope, "Subject: Backup\r\n\r\nDone\r\n");
+        assert_eq!(field(&payload, "From"), Some("<cron@example.com>"));
+    }
+
+    #[test]
+    fn missing_senders_show_unknown_sender() {
+        let mut handler = EmbedHandler::new().with_unknown_sender("someone");
+        let payload = render(
+            &mut handler,
+            test_envelope(None),
+            "Subject: Hi\r\n\r\nHi\r\n",
+        );
+        assert_eq!(field(&payload, "From"), Some("someone"));
+    }
+
+    /// Returns the username a sender-derived handler picks for a From header
+    ///
+    /// # Parameters
+    /// * `from` - the From header value
+    fn sender_username(from: &str) -> Option<String> {
+        let mut handler = EmbedHandler::new().with_username(WebhookUsername::Sender);
+        let message = format!("From: {}\r\n\r\nHi\r\n", from);
+        let payload = render(&mut handler, test_envelope(None), &message);
+        payload["username"].as_str().map(String::from)
+    }
+
+    #[test]
+    fn sender_username_uses_the_display_name() {
+        assert_eq!(
+            sender_username("Backup Server <backup@example.com>"),
+            Some("Backup Server".into())
+        );
+        assert_eq!(
+            sender_username("backup@example.com"),
+            Some("backup@example.com".into())
+        );
+    }
+
+    #[test]
+    fn sender_username_falls_back_when_discord_would_refuse_it() {
+        assert_eq!(sender_username("Discord Alerts <alerts@example.com>"), None);
+        assert_eq!(sender_username("\"CLYDE\" <clyde@example.com>"), None);
+    }
+
+    #[test]
+    fn long_sender_username_is_truncated() {
+        let name = "x".repeat(WEBHOOK_USERNAME_MAX * 2);
+        let username = sender_username(&format!("{} <a@example.com>", name)).expect("username");
+        assert_eq!(username.chars().count(), WEBHOOK_USERNAME_MAX);
+    }
+
+    /// Builds an envelope for one recipient
+    ///
+    /// # Parameters
+    /// * `mail` - the MAIL FROM command, if one was given
+    fn test_envelope(mail: Option<SmtpMail>) -> Envelope {
+        Envelope {
+            name: "test".into(),
+            local: None,
+            peer: None,
+            helo: None,
+            mail,
+            id: "id".into(),
+            rcpts: vec![SmtpPath::Direct(SmtpAddress::Mailbox(
+                "alerts".into(),
+                SmtpHost::Domain("example.com".into()),
+            ))],
+        }
+    }
+
+    #[test]
+    fn long_token_is_cut_at_the_limit() {
+        let token = "a".repeat(5000);
+        let chunks = split_body(&token, 1024);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 1024));
+        assert_eq!(chunks.concat(), token);
+    }
+
+    #[test]
+    fn body_at_the_limit_is_one_chunk() {
+        let body = "a".repeat(1024);
+        assert_eq!(split_body(&body, 1024), vec![body.clone()]);
+        let body = "a".repeat(1025);
+        let chunks = split_body(&body, 1024);
+        assert_eq!(chunks, vec!["a".repeat(1024), "a".into()]);
+    }
+
+    #[test]
+    fn multi_byte_characters_are_not_split() {
+        let body = "é😀".repeat(1500);
+        let chunks = split_body(&body, 1024);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 1024));
+        assert_eq!(chunks.concat(), body);
+    }
+
+    #[test]
+    fn urls_outside_code_are_wrapped() {
+        let body = "See https://example.com/status. Logs at (https://example.com/logs)\n\
+                    Docs: https://en.wikipedia.org/wiki/Mail_(protocol), <https://example.com/>\n\
+                    Run `curl https://example.com/api` or\n\
+                    ```\nhttps://example.com/raw\n```";
+        assert_eq!(
+            wrap_urls(body),
+            "See <https://example.com/status>. Logs at (<https://example.com/logs>)\n\
+             Docs: <https://en.wikipedia.org/wiki/Mail_(protocol)>, <https://example.com/>\n\
+             Run `curl https://example.com/api` or\n\
+             ```\nhttps://example.com/raw\n```"
+        );
+    }
+
+    #[test]
+    fn aligned_table_is_fenced() {
+        let mut handler = EmbedHandler::new().with_tables_fenced(true);
+        let message = "Subject: Disks\r\n\r\n\
+                       Usage report\n\
+                       Mount     Size     Used\n\
+                       /         20G      12G\n\
+                       /home     100G     80G\n\
+                       End";
+        let payload = render(&mut handler, test_envelope(None), message);
+        assert_eq!(
+            field(&payload, "Body"),
+            Some(
+                "Usage report\n```\nMount     Size     Used\n/         20G      12G\n\
+                 /home     100G     80G\n```\nEnd"
+            )
+        );
+    }
+
+    #[test]
+    fn differing_sender_is_shown() {
+        let mut handler = EmbedHandler::new().with_sender(true);
+        let message = "From: Alice <alice@example.com>\r\n\
+                       Sender: list@lists.example.com\r\n\
+                       Subject: Hi\r\n\r\nHi\r\n";
+        let payload = render(&mut handler, test_envelope(None), message);
+        assert_eq!(field(&payload, "Sender"), Some("list@lists.example.com"));
+    }
+
+    #[test]
+    fn sender_matching_from_is_hidden() {
+        let mut handler = EmbedHandler::new().with_sender(true);
+        let message = "From: Alice <alice@example.com>\r\n\
+                       Sender: alice@example.com\r\n\
+                       Subject: Hi\r\n\r\nHi\r\n";
+        let payload = render(&mut handler, test_envelope(None), message);
+        assert_eq!(field(&payload, "Sender"), None);
+    }
+
+    #[test]
+    fn blank_subject_falls_back() {
+        let message = "Subject:   \r\n\r\n\r\nDisk almost full\r\n";
+        let mut handler = EmbedHandler::new();
+        let payload = render(&mut handler, test_envelope(None), message);
+        assert_eq!(payload["embeds"][0]["title"], DEFAULT_TITLE);
+        let mut handler = EmbedHandler::new().with_subject_fallback(SubjectFallback::FirstLine);
+        let payload = render(&mut handler, test_envelope(None), message);
+        assert_eq!(payload["embeds"][0]["title"], "Disk almost full");
+    }
+
+    #[test]
+    fn long_single_body_is_continued_when_splitting() {
+        let body = "word ".repeat(500);
+        let message = format!("Subject: Log\r\n\r\n{}", body);
+        let mut handler = EmbedHandler::new().with_long_bodies_split(true);
+        let payload = render(&mut handler, test_envelope(None), &message);
+        let body_fields: Vec<&Value> = payload["embeds"][0]["fields"]
+            .as_array()
+            .expect("fields")
+            .iter()
+            .filter(|field| field["name"].as_str().unwrap_or("").starts_with("Body"))
+            .collect();
+        assert_eq!(body_fields.len(), 3);
+        assert_eq!(body_fields[1]["name"], "Body (cont.)");
+        let joined: Vec<&str> = body_fields
+            .iter()
+            .map(|field| field["value"].as_str().unwrap())
+            .collect();
+        assert_eq!(joined.join(" "), body.trim());
+        let mut handler = EmbedHandler::new();
+        let payload = render(&mut handler, test_envelope(None), &message);
+        assert_eq!(
+            field(&payload, "Body").map(|body| body.chars().count()),
+            Some(EMBED_FIELD_VALUE_MAX)
+        );
+    }
+
+    #[test]
+    fn split_at_whitespace_on_the_limit() {
+        let body = format!("{} {}", "a".repeat(1024), "b".repeat(10));
+        assert_eq!(
+            split_body(&body, 1024),
+            vec!["a".repeat(1024), "b".repeat(10)]
+        );
+        // Words are kept whole, with a multi-byte character right before the cut
+        let body = format!("{}é tail", "a".repeat(1020));
+        assert_eq!(
+            split_body(&body, 1024),
+            vec![format!("{}é", "a".repeat(1020)), "tail".into()]
+        );
+    }
+}
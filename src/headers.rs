@@ -0,0 +1,245 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+/// Header fields parsed from the top of a mail message
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    /// Header names and unfolded values, in message order
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Removes every field with a name from a raw message, ignoring case
+    ///
+    /// Folded continuation lines go with their field. Everything else, including the body and
+    /// line endings, is kept byte for byte.
+    ///
+    /// # Parameters
+    /// * `message` - the raw message data
+    /// * `name` - the field name
+    pub fn strip(message: &[u8], name: &str) -> Vec<u8> {
+        let (_, body_start) = Self::parse(message);
+        let mut stripped = Vec::with_capacity(message.len());
+        let mut removing = false;
+        let mut offset = 0;
+        while offset < body_start {
+            let end = message[offset..body_start]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(body_start, |pos| offset + pos + 1);
+            let line = &message[offset..end];
+            // A folded line belongs to the field before it
+            if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+                removing = line
+                    .iter()
+                    .position(|&b| b == b':')
+                    .is_some_and(|colon| line[..colon].eq_ignore_ascii_case(name.as_bytes()));
+            }
+            if !removing {
+                stripped.extend_from_slice(line);
+            }
+            offset = end;
+        }
+        stripped.extend_from_slice(&message[body_start..]);
+        stripped
+    }
+
+    /// Parses the header section of a message
+    ///
+    /// Returns the headers and the offset of the body. A message that doesn't start with a
+    /// header field is treated as having no headers at all.
+    ///
+    /// # Parameters
+    /// * `message` - the raw message data
+    pub fn parse(message: &[u8]) -> (Self, usize) {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        let mut offset = 0;
+        while offset < message.len() {
+            // Find the end of this line, including its line ending
+            let end = message[offset..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(message.len(), |pos| offset + pos + 1);
+            let line = String::from_utf8_lossy(&message[offset..end]);
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line.is_empty() {
+                // A blank line separates the headers from the body
+                return (Self { fields }, end);
+            }
+            if line.starts_with(&[' ', '\t'][..]) {
+                // Folded continuation of the previous field
+                match fields.last_mut() {
+                    Some((_, value)) => {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                    }
+                    None => return (Self::default(), 0),
+                }
+            } else {
+                match line.find(':') {
+                    Some(colon) if is_field_name(&line[..colon]) => fields.push((
+                        line[..colon].to_string(),
+                        line[colon + 1..].trim().to_string(),
+                    )),
+                    // Not a header, so this message has no header section
+                    _ if fields.is_empty() => return (Self::default(), 0),
+                    // Headers ended without a blank line, so the body starts here
+                    _ => return (Self { fields }, offset),
+                }
+            }
+            offset = end;
+        }
+        (Self { fields }, message.len())
+    }
+
+    /// Returns the value of the first field with a name, ignoring case
+    ///
+    /// # Parameters
+    /// * `name` - the field name
+    pub fn get<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.get_all(name).next()
+    }
+
+    /// Returns the values of every field with a name, ignoring case
+    ///
+    /// # Parameters
+    /// * `name` - the field name
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the hops in the Received trace, oldest first
+    ///
+    /// Each relay adds its Received field to the top of the message, so they are reversed here.
+    pub fn received_hops(&self) -> Vec<ReceivedHop> {
+        let mut hops: Vec<ReceivedHop> = self.get_all("Received").map(ReceivedHop::parse).collect();
+        hops.reverse();
+        hops
+    }
+
+    /// Returns the language tags declared in the Content-Language field, if any
+    pub fn content_language(&self) -> Vec<String> {
+        self.get("Content-Language")
+            .map(|languages| {
+                strip_comments(languages)
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes every field with a name, ignoring case, returning how many were removed
+    ///
+    /// # Parameters
+    /// * `name` - the field name
+    pub fn remove(&mut self, name: &str) -> usize {
+        let before = self.fields.len();
+        self.fields
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+        before - self.fields.len()
+    }
+
+    /// Iterates over every field name and value in message order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// One relay in a message's Received trace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReceivedHop {
+    /// Host the mail was received from
+    pub from: Option<String>,
+    /// Host that received the mail
+    pub by: Option<String>,
+}
+
+impl ReceivedHop {
+    /// Parses the from and by hosts out of a Received field, ignoring comments and the date
+    ///
+    /// # Parameters
+    /// * `value` - the unfolded Received field value
+    pub fn parse(value: &str) -> Self {
+        // Everything after the semicolon is the date
+        let clauses = strip_comments(value.split(';').next().unwrap_or(""));
+        let mut hop = Self::default();
+        let mut tokens = clauses.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let slot = if token.eq_ignore_ascii_case("from") {
+                &mut hop.from
+            } else if token.eq_ignore_ascii_case("by") {
+                &mut hop.by
+            } else {
+                continue;
+            };
+            if slot.is_none() {
+                *slot = tokens.next().map(String::from);
+            }
+        }
+        hop
+    }
+}
+
+impl fmt::Display for ReceivedHop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} → {}",
+            self.from.as_deref().unwrap_or("?"),
+            self.by.as_deref().unwrap_or("?")
+        )
+    }
+}
+
+/// Removes parenthesized comments, which may nest, from a header value
+///
+/// # Parameters
+/// * `value` - the header value
+fn strip_comments(value: &str) -> String {
+    let mut depth = 0usize;
+    let mut stripped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => {
+                depth -= 1;
+                // Keep the words either side of a comment apart
+                stripped.push(' ');
+            }
+            _ if depth == 0 => stripped.push(c),
+            _ => (),
+        }
+    }
+    stripped
+}
+
+/// Checks whether a string is a valid header field name
+///
+/// # Parameters
+/// * `name` - the candidate field name
+fn is_field_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+}
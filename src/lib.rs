@@ -14,22 +14,77 @@
 // You should have received a copy of the GNU General Public License
 // along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod annotate;
+pub mod commands;
 pub mod config;
+pub mod conversation;
+pub mod dedup;
 pub mod discord;
+pub mod handler;
+pub mod headers;
+pub mod route;
 pub mod smtp;
+pub mod spam;
+pub mod systemd;
+pub mod throttle;
+pub mod zip;
 
-use crate::discord::DiscordWebhookAuth;
+use crate::commands::{CommandAction, CommandRecipients};
+use crate::conversation::ConversationThreads;
+use crate::dedup::MessageIdCache;
+use crate::discord::{Attachment, DiscordWebhookAuth, SenderNormalization};
+use crate::handler::EmbedHandler;
+use crate::headers::Headers;
+use crate::route::{RouteDestination, Routes, RoutingDefault};
+use crate::smtp::{AllowedSenders, DataIdleWatch, DataRateMonitor, LineEndings, ServedDomains};
+use crate::spam::{SpamAction, SpamCheck};
+use crate::throttle::{PostLimiter, ThrottledLogger};
 use bytes::Bytes;
 use futures::future::{self, FutureResult};
 use futures::sink::Sink;
 use futures::{Async, AsyncSink, Poll, StartSend};
+use log::{debug, error, info, warn};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, USER_AGENT};
+use reqwest::StatusCode;
+use samotop::model::command::{SmtpMail, SmtpPath};
 use samotop::model::mail::{AcceptRecipientRequest, AcceptRecipientResult, Envelope, QueueResult};
 use samotop::service::{Mail, MailGuard, MailQueue, NamedService};
+use serde_json::{json, Value};
 use serenity::builder::ExecuteWebhook;
+use serenity::http::request::RequestBuilder;
+use serenity::http::routing::RouteInfo;
+use serenity::http::HttpError;
 use serenity::model::channel::Message;
 use serenity::model::webhook::Webhook;
+use std::borrow::Cow;
 use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// SMTP service name used in the greeting when none is configured
+pub const DEFAULT_SERVICE_NAME: &str = "DiscordMailer";
+/// Message content sent when a handler produces a message Discord would reject as empty
+pub const DEFAULT_EMPTY_MESSAGE: &str = "(empty message)";
+/// Discord's limit on the combined length of the text in a message's embeds
+pub const DEFAULT_MAX_EMBED_CHARS: usize = 6000;
+/// Default number of messages a mail too long for one is posted across
+pub const DEFAULT_MAX_SPLIT_MESSAGES: usize = 5;
+/// Default size above which a message body aborts the transaction, in bytes
+pub const DEFAULT_MAX_BODY_BYTES: usize = 25 * 1024 * 1024;
+/// Default number of times a transient send failure is retried
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+/// Default delay before the first retry of a send
+pub const DEFAULT_RETRY_BASE: Duration = Duration::from_millis(500);
+/// Default time after the first attempt beyond which a send isn't retried
+pub const DEFAULT_RETRY_MAX_TOTAL: Duration = Duration::from_secs(30);
+/// User-Agent sent with webhook requests when none is configured
+pub const DEFAULT_USER_AGENT: &str = concat!("smtp_discord_bridge/", env!("CARGO_PKG_VERSION"));
+/// Time between checks for the pause control file
+pub const PAUSE_FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// This trait defines the conversion between received mail and discord webhook messages
 pub trait MailToDiscord {
@@ -37,11 +92,44 @@ pub trait MailToDiscord {
     ///
     /// # Parameters
     /// * `envelope` - contains information such as sender, recipients, IP addresses, and SMTP
-    /// handshake information
+    ///   handshake information
     /// * `body` - contains the binary body of the mail
     /// * `webhook_builder` - Serenity `ExecuteWebhook` that allows for controlling the content of
-    /// a webhook message
-    fn handle(&mut self, envelope: Envelope, body: Vec<u8>, webhook_builder: &mut ExecuteWebhook);
+    ///   a webhook message
+    fn handle(&mut self, envelope: &Envelope, body: &[u8], webhook_builder: &mut ExecuteWebhook);
+
+    /// Fills in a short summary of a mail whose raw message is uploaded along with it
+    ///
+    /// By default the summary has the sender, recipients, subject and size.
+    ///
+    /// # Parameters
+    /// * `envelope` - the mail's envelope
+    /// * `body` - the raw mail
+    /// * `webhook_builder` - the payload to fill in
+    fn summarize(
+        &mut self,
+        envelope: &Envelope,
+        body: &[u8],
+        webhook_builder: &mut ExecuteWebhook,
+    ) {
+        handler::attachment_summary(
+            envelope,
+            body,
+            handler::DEFAULT_UNKNOWN_SENDER,
+            webhook_builder,
+        );
+    }
+
+    /// Returns a file holding the mail body, if the handler shows only part of it
+    ///
+    /// It is uploaded along with the message from `handle`. By default nothing is attached.
+    ///
+    /// # Parameters
+    /// * `envelope` - the mail's envelope
+    /// * `body` - the raw mail
+    fn body_attachment(&mut self, _envelope: &Envelope, _body: &[u8]) -> Option<Attachment> {
+        None
+    }
 }
 
 /// Custom mail handler that sends messages to Discord via a webhook
@@ -51,6 +139,38 @@ pub struct DiscordMailer<T> {
     name: String,
     /// Stores webhook connector and message handler
     webhook_sender: Arc<Mutex<WebhookSender<T>>>,
+    /// How line endings in the message data are treated
+    line_endings: LineEndings,
+    /// Whether the last background check found the webhook valid
+    webhook_ok: Arc<AtomicBool>,
+    /// Whether all mail is being turned away with a temporary failure
+    paused: Arc<AtomicBool>,
+    /// Domains recipients are accepted for
+    served_domains: Arc<ServedDomains>,
+    /// Number of transactions allowed on one connection, if limited
+    max_messages_per_connection: Option<usize>,
+    /// Number of transactions started on this connection
+    messages: ConnectionCounter,
+    /// Recipient addresses that control the bridge
+    commands: Arc<CommandRecipients>,
+    /// Minimum rate message data must arrive at, if enforced
+    data_rate: Option<DataRateMonitor>,
+    /// When this connection started
+    connected: ConnectionClock,
+    /// Whether the time from connection to queueing is shown in the embed
+    show_timing: bool,
+    /// Senders allowed to relay mail, if restricted
+    allowed_senders: Option<Arc<AllowedSenders>>,
+    /// How sender addresses are normalized before they are matched
+    sender_normalization: Arc<SenderNormalization>,
+    /// Size above which a message body aborts the transaction
+    max_body_bytes: usize,
+    /// Transfers whose message data is watched for stalls, if they are caught
+    data_idle: Option<DataIdleWatch>,
+    /// Recipient routes, checked when unrouted recipients are rejected
+    routes: Arc<Routes<RouteDestination>>,
+    /// What happens to mail for recipients no route matches
+    routing_default: RoutingDefault,
 }
 
 impl<T> DiscordMailer<T>
@@ -69,11 +189,141 @@ where
         handler: T,
     ) -> Result<Self, serenity::Error> {
         // Create the webhook sender
-        let webhook_sender = WebhookSender::new(webhook_auth, handler)?;
+        let webhook_sender = WebhookSender::new(webhook_auth, handler, &HeaderMap::new())?;
+
+        Ok(Self::from_sender(name, webhook_sender))
+    }
 
-        Ok(Self {
+    /// Constructs the mailer around an already configured webhook sender
+    ///
+    /// # Parameters
+    /// * `name` - SMTP service name
+    /// * `webhook_sender` - Webhook connector and message handler
+    fn from_sender(name: &str, webhook_sender: WebhookSender<T>) -> Self {
+        Self {
             name: name.into(),
             webhook_sender: Arc::new(Mutex::new(webhook_sender)),
+            line_endings: LineEndings::default(),
+            webhook_ok: Arc::new(AtomicBool::new(true)),
+            paused: Arc::new(AtomicBool::new(false)),
+            served_domains: Arc::new(ServedDomains::default()),
+            max_messages_per_connection: None,
+            messages: ConnectionCounter::default(),
+            commands: Arc::new(CommandRecipients::default()),
+            data_rate: None,
+            connected: ConnectionClock::default(),
+            show_timing: false,
+            allowed_senders: None,
+            sender_normalization: Arc::new(SenderNormalization::default()),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            data_idle: None,
+            routes: Arc::new(Routes::default()),
+            routing_default: RoutingDefault::default(),
+        }
+    }
+
+    /// Returns whether the last background check found the webhook valid
+    ///
+    /// Always true if no pinger is running.
+    pub fn webhook_ok(&self) -> bool {
+        self.webhook_ok.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> DiscordMailer<T> {
+    /// Returns whether all mail is being turned away with a temporary failure
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Starts or stops turning away all mail with a temporary failure
+    ///
+    /// Senders keep paused mail queued and retry later, so nothing is lost while paused.
+    ///
+    /// # Parameters
+    /// * `paused` - whether to turn away mail
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Starts a background thread that pauses the mailer while a control file exists
+    ///
+    /// Creating the file pauses the mailer and removing it resumes it, without restarting.
+    ///
+    /// # Parameters
+    /// * `path` - path of the control file
+    /// * `interval` - time between checks for the file
+    pub fn spawn_pause_watcher(&self, path: PathBuf, interval: Duration) -> thread::JoinHandle<()> {
+        let paused = Arc::clone(&self.paused);
+        thread::spawn(move || {
+            // Only act when the file comes or goes, so a mute command isn't undone straight away
+            let mut existed = false;
+            loop {
+                let exists = path.exists();
+                if exists != existed {
+                    paused.store(exists, Ordering::SeqCst);
+                    if exists {
+                        warn!("Pause file {} found, rejecting all mail", path.display());
+                    } else {
+                        info!("Pause file {} removed, accepting mail", path.display());
+                    }
+                    existed = exists;
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+}
+
+impl<T> DiscordMailer<T>
+where
+    T: Send + 'static,
+{
+    /// Starts a background thread that periodically checks the webhook still exists
+    ///
+    /// Changes in the webhook's status are logged, and the latest status is available from
+    /// `webhook_ok`.
+    ///
+    /// # Parameters
+    /// * `interval` - time between checks
+    pub fn spawn_pinger(&self, interval: Duration) -> thread::JoinHandle<()> {
+        let webhook_sender = Arc::clone(&self.webhook_sender);
+        let webhook_ok = Arc::clone(&self.webhook_ok);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let result = match webhook_sender.lock() {
+                Ok(sender) => sender.check(),
+                // Nothing can send through a poisoned sender, so there is nothing left to watch
+                Err(_) => break,
+            };
+            let was_ok = webhook_ok.swap(result.is_ok(), Ordering::SeqCst);
+            match result {
+                Ok(()) if !was_ok => info!("Discord webhook is reachable again"),
+                Ok(()) => debug!("Discord webhook check succeeded"),
+                Err(e) if was_ok => error!("Discord webhook check failed: {:?}", e),
+                Err(e) => debug!("Discord webhook check still failing: {:?}", e),
+            }
+        })
+    }
+
+    /// Starts a background thread that periodically fetches the webhook again
+    ///
+    /// This keeps the cached channel and name in step with changes made in Discord.
+    ///
+    /// # Parameters
+    /// * `interval` - time between fetches
+    pub fn spawn_refresher(&self, interval: Duration) -> thread::JoinHandle<()> {
+        let webhook_sender = Arc::clone(&self.webhook_sender);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let result = match webhook_sender.lock() {
+                Ok(mut sender) => sender.refresh(),
+                // Nothing can send through a poisoned sender, so there is nothing to refresh
+                Err(_) => break,
+            };
+            if let Err(e) = result {
+                warn!("Failed to refresh Discord webhook: {:?}", e);
+            }
         })
     }
 }
@@ -96,8 +346,48 @@ impl<T> MailGuard for DiscordMailer<T> {
     ///
     /// # Parameters
     /// * `request` - request to send mail containing information such as sender, recipient, and IP
-    /// addresses
+    ///   addresses
     fn accept(&self, request: AcceptRecipientRequest) -> Self::Future {
+        // Only relay for allowed senders, including mail to command recipients
+        if let Some(allowed_senders) = &self.allowed_senders {
+            if !allowed_senders.allows(request.mail.as_ref(), &self.sender_normalization) {
+                info!(
+                    "Rejecting recipient {} from a sender that isn't allowed",
+                    request.rcpt
+                );
+                return future::ok(AcceptRecipientResult::Rejected);
+            }
+        }
+        // Command recipients always get through, so the bridge can be unmuted
+        if self.commands.action(&request.rcpt).is_some() {
+            return future::ok(AcceptRecipientResult::Accepted(request.rcpt));
+        }
+        // Tell senders to try again later while paused
+        if self.paused.load(Ordering::SeqCst) {
+            return future::ok(AcceptRecipientResult::Failed);
+        }
+        // Make the client reconnect once it has sent its share of mail on this connection
+        if let Some(max) = self.max_messages_per_connection {
+            if self.messages.get() >= max {
+                return future::ok(AcceptRecipientResult::Failed);
+            }
+        }
+        // Refuse to relay for domains we don't serve
+        if !self.served_domains.accepts(&request.rcpt) {
+            info!(
+                "Rejecting recipient {} in a domain that isn't served",
+                request.rcpt
+            );
+            return future::ok(AcceptRecipientResult::Rejected);
+        }
+        // Refuse recipients with no route if asked to, though the postmaster is always taken
+        if self.routing_default == RoutingDefault::Reject
+            && request.rcpt != SmtpPath::Postmaster
+            && !self.routes.matches(&request.rcpt)
+        {
+            info!("Rejecting recipient {} with no route", request.rcpt);
+            return future::ok(AcceptRecipientResult::Rejected);
+        }
         // Accept the recipient as given
         future::ok(AcceptRecipientResult::Accepted(request.rcpt))
     }
@@ -112,21 +402,172 @@ impl<T> MailQueue for DiscordMailer<T> {
     ///
     /// # Parameters
     /// `envelope` - the message's envelope
-    fn mail(&self, envelope: Envelope) -> Self::MailFuture {
+    fn mail(&self, mut envelope: Envelope) -> Self::MailFuture {
+        self.messages.increment();
+        // Pull out command recipients, leaving the ones the mail is posted for
+        let mut actions = Vec::new();
+        envelope
+            .rcpts
+            .retain(|rcpt| match self.commands.action(rcpt) {
+                Some(action) => {
+                    actions.push(action);
+                    false
+                }
+                None => true,
+            });
         // Queue a new piece of mail with the given id
-        future::ok(Some(Self::Mail::new(envelope, self.webhook_sender.clone())))
+        let mut mail = Self::Mail::new(envelope, self.webhook_sender.clone(), self.line_endings);
+        mail.actions = actions;
+        mail.paused = Arc::clone(&self.paused);
+        mail.data_rate = self.data_rate;
+        mail.max_body_bytes = self.max_body_bytes;
+        // Start watching for stalls now, in case no data ever arrives
+        if let (Some(watch), Some(peer)) = (&self.data_idle, mail.envelope.peer) {
+            watch.record(peer);
+            mail.data_idle = Some((watch.clone(), peer));
+        }
+        if self.show_timing {
+            mail.connected = Some(self.connected.0);
+        }
+        future::ok(Some(mail))
+    }
+}
+
+/// Counts transactions on a single connection
+///
+/// The session service clones the mailer for each connection, so a clone starts again from zero.
+#[derive(Default)]
+struct ConnectionCounter(AtomicUsize);
+
+impl ConnectionCounter {
+    /// Returns the number of transactions counted
+    fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Counts another transaction
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Clone for ConnectionCounter {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Records when a connection started
+///
+/// Like `ConnectionCounter`, a clone made for a new connection starts again from now.
+struct ConnectionClock(Instant);
+
+impl Default for ConnectionClock {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Clone for ConnectionClock {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// A webhook fetched from Discord, along with the thread in its channel mail is posted to
+#[derive(Clone)]
+struct WebhookTarget {
+    /// Discord webhook handle
+    webhook: Webhook,
+    /// Thread posted to instead of the webhook's channel, if any
+    thread_id: Option<u64>,
+    /// Embed color used in place of the handler's, if any
+    color: Option<u32>,
+    /// Handler used in place of the sender's, for routes with their own format
+    handler: Option<EmbedHandler>,
+}
+
+impl WebhookTarget {
+    /// Returns the token to build serenity's execute route with, carrying the thread if any
+    ///
+    /// serenity's route has no thread parameter and appends `?wait=true` to the token itself, so
+    /// the query goes after the token and serenity's is left in a fragment, which isn't sent.
+    fn execute_token(&self) -> Cow<'_, str> {
+        match self.thread_id {
+            Some(thread_id) => {
+                format!("{}?wait=true&thread_id={}#", self.webhook.token, thread_id).into()
+            }
+            None => Cow::from(self.webhook.token.as_str()),
+        }
+    }
+
+    /// Applies the target's own look to a payload, such as its embed color
+    ///
+    /// # Parameters
+    /// * `webhook_builder` - the payload built by the mail handler
+    fn restyle(&self, webhook_builder: &mut ExecuteWebhook) {
+        if let Some(color) = self.color {
+            set_embed_color(webhook_builder, color);
+        }
+    }
+
+    /// Checks whether two targets post to the same place
+    ///
+    /// # Parameters
+    /// * `other` - the other target
+    fn same_as(&self, other: &Self) -> bool {
+        self.webhook.id == other.webhook.id && self.thread_id == other.thread_id
     }
 }
 
 /// Sends a message using a webhook
 struct WebhookSender<T> {
-    /// Serenity HTTP client
-    http: serenity::http::client::Http,
+    /// Connection to Discord, shared with sends made outside the lock
+    client: WebhookClient,
     /// Discord webhook handle
     webhook: Webhook,
+    /// Thread in the webhook's channel to post to, if any
+    thread_id: Option<u64>,
     /// Object that can convert emails to discord webhook messages
     /// Mutexed because the function that does this takes a mutable reference to itself
     handler: T,
+    /// Message content sent in place of a payload the handler left empty
+    empty_message: String,
+    /// Logs send failures without flooding the log during an outage
+    error_log: ThrottledLogger,
+    /// Number of messages sent to Discord
+    sent: u64,
+    /// Number of messages that failed to send
+    failed: u64,
+    /// Recently posted Message-IDs, if repeats are dropped
+    message_ids: Option<MessageIdCache>,
+    /// Threads mail conversations were posted to, if each conversation gets its own
+    conversations: Option<ConversationThreads>,
+    /// How sender addresses are normalized into dedup keys
+    sender_normalization: SenderNormalization,
+    /// Check of upstream spam headers, if spam is turned away
+    spam_check: Option<SpamCheck>,
+    /// Combined length of embed text above which the raw mail is uploaded instead, or fields
+    /// are dropped to fit if it can't be
+    max_embed_chars: usize,
+    /// Whether embeds too long for one message are posted across several instead
+    split_messages: bool,
+    /// Most messages one mail is posted across, past which the rest is left out
+    max_split_messages: usize,
+    /// Size above which the raw mail is uploaded as a file, if it ever is
+    attach_raw_above: Option<usize>,
+    /// Whether uploaded files are bundled into a zip
+    zip_attachments: bool,
+    /// Header fields a message must have to be accepted
+    required_headers: Vec<String>,
+    /// Cap on messages posted per time window, if any
+    post_limit: Option<PostLimiter>,
+    /// Webhooks mail for particular recipients is posted to instead
+    routes: Routes<WebhookTarget>,
+    /// What happens to mail for recipients no route matches
+    routing_default: RoutingDefault,
+    /// How transient send failures are retried
+    retry: RetryPolicy,
 }
 
 impl<T> WebhookSender<T>
@@ -138,149 +579,2648 @@ where
     /// # Parameters
     /// * `webhook_auth` - Discord webhook id and auth info
     /// * `handler` - Object that converts mail to Discord webhook messages
-    fn new(webhook_auth: &DiscordWebhookAuth, handler: T) -> Result<Self, serenity::Error> {
-        // Create the Discord http client
-        let http = serenity::http::client::Http::new_with_token("");
+    /// * `headers` - Extra HTTP headers sent with every request, which may replace the User-Agent
+    fn new(
+        webhook_auth: &DiscordWebhookAuth,
+        handler: T,
+        headers: &HeaderMap,
+    ) -> Result<Self, serenity::Error> {
+        let request_headers = request_headers(headers);
+        // Create the Discord http client, carrying the extra headers for requests made directly
+        let client = reqwest::blocking::Client::builder()
+            .use_rustls_tls()
+            .default_headers(request_headers.clone())
+            .build()
+            .map_err(HttpError::from)?;
+        let client = Arc::new(client);
+        let http = serenity::http::client::Http::new(Arc::clone(&client), "");
         // Get a reference to the webhook
-        let webhook = http
-            .as_ref()
-            .get_webhook_with_token(webhook_auth.id, &webhook_auth.token)?;
+        let webhook = get_webhook(
+            &http,
+            &request_headers,
+            webhook_auth.id,
+            &webhook_auth.token,
+        )?;
+        let mut webhook_sender = Self::from_webhook(client, request_headers, webhook, handler);
+        webhook_sender.thread_id = webhook_auth.thread_id;
+        Ok(webhook_sender)
+    }
 
-        Ok(Self {
-            http,
+    /// Constructs the sender around an already fetched webhook
+    ///
+    /// # Parameters
+    /// * `client` - HTTP client carrying any extra headers
+    /// * `request_headers` - HTTP headers sent with every request
+    /// * `webhook` - Discord webhook handle
+    /// * `handler` - Object that converts mail to Discord webhook messages
+    fn from_webhook(
+        client: Arc<reqwest::blocking::Client>,
+        request_headers: HeaderMap,
+        webhook: Webhook,
+        handler: T,
+    ) -> Self {
+        Self {
+            client: WebhookClient::new(client, request_headers),
             webhook,
+            thread_id: None,
             handler,
-        })
+            empty_message: DEFAULT_EMPTY_MESSAGE.into(),
+            error_log: ThrottledLogger::default(),
+            sent: 0,
+            failed: 0,
+            message_ids: None,
+            conversations: None,
+            sender_normalization: SenderNormalization::default(),
+            spam_check: None,
+            max_embed_chars: DEFAULT_MAX_EMBED_CHARS,
+            split_messages: false,
+            max_split_messages: DEFAULT_MAX_SPLIT_MESSAGES,
+            attach_raw_above: None,
+            zip_attachments: false,
+            required_headers: Vec::new(),
+            post_limit: None,
+            routes: Routes::default(),
+            routing_default: RoutingDefault::default(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Prepares a message based on a given envelope and body, ready to post without the lock
+    ///
+    /// The message goes to the routed webhook of each recipient, or the default webhook, posting
+    /// once to each. Mail above the attachment size, or whose embeds are too long for one message
+    /// and aren't split, is uploaded whole as `message.eml` under a short summary. Mail too large
+    /// for Discord's upload limit is posted as usual, with a note when it was meant to be attached.
+    /// Otherwise the handler may attach the whole body when it shows only part of it.
+    ///
+    /// # Parameters
+    /// * `envelope`
+    /// * `body`
+    /// * `timing` - time from connection to queueing, if it is shown
+    fn prepare(&mut self, envelope: Envelope, body: Vec<u8>, timing: Option<Duration>) -> Delivery {
+        let mut targets = self.targets(&envelope.rcpts);
+        let conversation = match &mut self.conversations {
+            Some(conversations) => {
+                let (headers, _) = Headers::parse(&body);
+                let references = conversation::references(&headers);
+                // Replies go to their conversation's thread, unless the route names a thread
+                for target in &mut targets {
+                    if target.thread_id.is_none() {
+                        target.thread_id = conversations.find(target.webhook.id.0, &references);
+                    }
+                }
+                let title = headers
+                    .get("Subject")
+                    .map(str::trim)
+                    .filter(|subject| !subject.is_empty())
+                    .unwrap_or(handler::DEFAULT_TITLE);
+                Some(Conversation {
+                    message_id: headers.get("Message-ID").map(|id| id.trim().into()),
+                    thread_name: handler::truncate(title, THREAD_NAME_MAX),
+                })
+            }
+            None => None,
+        };
+        // Routes with their own format get messages built by their own handler
+        let (routed, targets): (Vec<_>, Vec<_>) = targets
+            .into_iter()
+            .partition(|target| target.handler.is_some());
+        let mut posts = Vec::new();
+        if !targets.is_empty() {
+            let (payloads, attachment) = self.payloads(&envelope, &body, timing);
+            posts.push(Post {
+                targets,
+                payloads,
+                attachment,
+            });
+        }
+        for target in routed {
+            let mut handler = target
+                .handler
+                .clone()
+                .expect("routed targets have a handler");
+            let (_, layout) = self.layout();
+            let (payloads, attachment) = layout.payloads(&mut handler, &envelope, &body, timing);
+            posts.push(Post {
+                targets: vec![target],
+                payloads,
+                attachment,
+            });
+        }
+        Delivery {
+            client: self.client.clone(),
+            retry: self.retry,
+            posts,
+            conversation,
+        }
+    }
+
+    /// Works out which webhooks the recipients are routed to, listing each only once
+    ///
+    /// Recipients no route matches go to the default webhook, unless they are dropped.
+    ///
+    /// # Parameters
+    /// * `rcpts` - the recipient paths
+    fn targets(&self, rcpts: &[SmtpPath]) -> Vec<WebhookTarget> {
+        let mut targets: Vec<WebhookTarget> = Vec::new();
+        for rcpt in rcpts {
+            let target = match self.routes.resolve(rcpt) {
+                Some(target) => target.clone(),
+                None if self.routing_default == RoutingDefault::Drop => continue,
+                None => self.default_target(),
+            };
+            if targets.iter().all(|other| !other.same_as(&target)) {
+                targets.push(target);
+            }
+        }
+        targets
     }
 
-    /// Sends a message based on a given envelope and body
+    /// Builds the messages posted for a mail with the sender's own handler
     ///
     /// # Parameters
     /// * `envelope`
     /// * `body`
-    fn send_messsage(
+    /// * `timing` - time from connection to queueing, if it is shown
+    fn payloads(
         &mut self,
-        envelope: Envelope,
-        body: Vec<u8>,
-    ) -> Result<Option<Message>, serenity::Error> {
-        // Get a mutable reference to the handler so we don't double borrow self
-        let handler = &mut self.handler;
-        // Run the webhook handler and produce a message
-        self.webhook.execute(&self.http, true, |w| {
-            handler.handle(envelope, body, w);
-            w
-        })
+        envelope: &Envelope,
+        body: &[u8],
+        timing: Option<Duration>,
+    ) -> (Vec<ExecuteWebhook>, Option<Attachment>) {
+        let (handler, layout) = self.layout();
+        layout.payloads(handler, envelope, body, timing)
     }
-}
 
-/// Builder constructor for the Discord mailer
-pub struct DiscordMailerBuilder {
-    name: Option<String>,
+    /// Returns the sender's handler along with how messages are laid out
+    fn layout(&mut self) -> (&mut T, PayloadLayout<'_>) {
+        let layout = PayloadLayout {
+            attach_raw_above: self.attach_raw_above,
+            zip_attachments: self.zip_attachments,
+            max_embed_chars: self.max_embed_chars,
+            split_messages: self.split_messages,
+            max_split_messages: self.max_split_messages,
+            empty_message: &self.empty_message,
+        };
+        (&mut self.handler, layout)
+    }
 }
 
-impl DiscordMailerBuilder {
-    /// Constructor
-    pub fn new() -> Self {
-        Self { name: None }
-    }
+/// How the messages for a mail are laid out, whichever handler builds them
+struct PayloadLayout<'a> {
+    /// Size above which the raw mail is uploaded as a file, if it ever is
+    attach_raw_above: Option<usize>,
+    /// Whether uploaded files are bundled into a zip
+    zip_attachments: bool,
+    /// Combined length of embed text above which the raw mail is uploaded instead
+    max_embed_chars: usize,
+    /// Whether embeds too long for one message are posted across several instead
+    split_messages: bool,
+    /// Most messages one mail is posted across
+    max_split_messages: usize,
+    /// Message content sent in place of a payload the handler left empty
+    empty_message: &'a str,
+}
 
-    /// Adds an SMTP service name to the service
+impl PayloadLayout<'_> {
+    /// Builds the messages posted for a mail, and the raw mail if it is uploaded with them
     ///
     /// # Parameters
-    /// * `name` - the service name
-    pub fn with_name(mut self, name: &str) -> Self {
-        self.name = Some(name.into());
-        self
+    /// * `handler` - Object that converts mail to Discord webhook messages
+    /// * `envelope`
+    /// * `body`
+    /// * `timing` - time from connection to queueing, if it is shown
+    fn payloads<H: MailToDiscord>(
+        &self,
+        handler: &mut H,
+        envelope: &Envelope,
+        body: &[u8],
+        timing: Option<Duration>,
+    ) -> (Vec<ExecuteWebhook>, Option<Attachment>) {
+        let size = body.len();
+        let attach = self
+            .attach_raw_above
+            .is_some_and(|threshold| size > threshold);
+        let mut payload = ExecuteWebhook::default();
+        let raw_upload = if attach {
+            Some(self.upload(raw_file(body), envelope, body))
+        } else {
+            None
+        };
+        let attachment = match &raw_upload {
+            Some(Ok(file)) => {
+                handler.summarize(envelope, body, &mut payload);
+                Some(file.clone())
+            }
+            _ => {
+                // The handler may show only part of the body, with the whole of it attached
+                let body_file = handler
+                    .body_attachment(envelope, body)
+                    .and_then(|file| self.upload(file, envelope, body).ok());
+                // Run the webhook handler and produce a message, once for every webhook and retry
+                handler.handle(envelope, body, &mut payload);
+                // A message too long to post whole is uploaded instead, unless it can be split
+                let too_long =
+                    !self.split_messages && payload_chars(&payload) > self.max_embed_chars;
+                match raw_upload {
+                    None if too_long => match self.upload(raw_file(body), envelope, body) {
+                        Ok(file) => {
+                            warn!(
+                                "Message too long for Discord at {} characters, uploading the mail instead",
+                                payload_chars(&payload)
+                            );
+                            payload = ExecuteWebhook::default();
+                            handler.summarize(envelope, body, &mut payload);
+                            Some(file)
+                        }
+                        Err(_) => body_file,
+                    },
+                    _ => body_file,
+                }
+            }
+        };
+        if let Some(Err(upload_size)) = raw_upload {
+            warn!(
+                "Attachment of {} is over Discord's upload limit, posting the mail without it",
+                handler::format_size(upload_size)
+            );
+            add_field(
+                &mut payload,
+                json!({
+                    "name": "Attachment",
+                    "value": format!(
+                        "Too large to attach at {}, so the body may be cut short",
+                        handler::format_size(upload_size)
+                    ),
+                    "inline": false,
+                }),
+            );
+        }
+        if let Some(timing) = timing {
+            add_timing_field(&mut payload, timing, size);
+        }
+        // Discord rejects a message with neither content nor embeds
+        if is_empty_payload(&payload) {
+            warn!("Mail handler produced an empty message, sending placeholder instead");
+            payload.content(self.empty_message);
+        }
+        let mut payloads = if self.split_messages {
+            let mut payloads = split_payload(payload, self.max_embed_chars);
+            cap_parts(&mut payloads, self.max_split_messages);
+            payloads
+        } else {
+            vec![payload]
+        };
+        // Discord rejects the whole message if the embeds are too long together
+        for payload in &mut payloads {
+            fit_embeds(payload, self.max_embed_chars);
+        }
+        (payloads, attachment)
     }
 
-    /// Constructs the Discord mailer
+    /// Returns a file as it is uploaded, zipped if zipping is on
+    ///
+    /// A file over Discord's upload limit is an error carrying the size it would be uploaded at.
     ///
     /// # Parameters
-    /// * `webhook_auth` - Discord webhook id and auth info
-    /// * `handler` - Object used to generate messages from email
-    pub fn build<T>(
-        self,
-        webhook_auth: &DiscordWebhookAuth,
-        handler: T,
-    ) -> Result<DiscordMailer<T>, serenity::Error>
-    where
-        T: Clone + MailToDiscord,
-    {
-        let name = self.name.unwrap_or_else(|| "DiscordMailer".into());
-        DiscordMailer::new(&name, webhook_auth, handler)
+    /// * `file` - the file to upload
+    /// * `envelope` - the mail's envelope, whose id names the zip if the mail has no subject
+    /// * `body` - the raw mail, whose subject names the zip
+    fn upload(
+        &self,
+        file: Attachment,
+        envelope: &Envelope,
+        body: &[u8],
+    ) -> Result<Attachment, usize> {
+        let file = if self.zip_attachments {
+            match zip::archive(&[(&file.file_name, &file.data)]) {
+                Ok(data) => Attachment::new(&zip_name(envelope, body), "application/zip", data),
+                Err(e) => {
+                    warn!(
+                        "Failed to zip {}, uploading it as is: {}",
+                        file.file_name, e
+                    );
+                    file
+                }
+            }
+        } else {
+            file
+        };
+        if file.data.len() > discord::MAX_UPLOAD_BYTES {
+            Err(file.data.len())
+        } else {
+            Ok(file)
+        }
     }
 }
 
-/// Reads mail data over SMTP and sends it over Discord
-pub struct DiscordMailSink<T> {
-    /// The message's envelope
-    envelope: Envelope,
-    /// Buffer used to store the message body
-    body: Vec<u8>,
-    /// MPSC sender used to send the message to the Discord sink
-    sink: Arc<Mutex<WebhookSender<T>>>,
+/// Returns the raw mail to upload
+///
+/// # Parameters
+/// * `body` - the raw mail
+fn raw_file(body: &[u8]) -> Attachment {
+    // Blind copy recipients are meant to stay hidden from the other recipients
+    let eml = Headers::strip(body, "Bcc");
+    Attachment::new("message.eml", "message/rfc822", eml)
 }
 
-impl<T> DiscordMailSink<T> {
+/// Most characters of a subject or envelope id a zip is named with
+const ZIP_NAME_MAX: usize = 64;
+
+/// Returns the name of the zip a mail's files are uploaded in, after its subject or envelope id
+///
+/// # Parameters
+/// * `envelope` - the mail's envelope
+/// * `body` - the raw mail
+fn zip_name(envelope: &Envelope, body: &[u8]) -> String {
+    let (headers, _) = Headers::parse(body);
+    let name = |value: &str| {
+        value
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(ZIP_NAME_MAX)
+            .collect::<String>()
+            .trim_matches('_')
+            .to_string()
+    };
+    let subject = headers.get("Subject").map(name).unwrap_or_default();
+    let name = if subject.is_empty() {
+        name(&envelope.id)
+    } else {
+        subject
+    };
+    format!("{}.zip", name)
+}
+
+/// Connection to Discord, cheap to clone so a send can run without holding the sender's lock
+#[derive(Clone)]
+struct WebhookClient {
+    /// Serenity HTTP client
+    http: Arc<serenity::http::client::Http>,
+    /// HTTP headers sent with every request, including the User-Agent
+    request_headers: HeaderMap,
+}
+
+impl WebhookClient {
     /// Constructor
     ///
     /// # Parameters
-    /// * `envelope` - The message's envelope
-    /// * `sink` - MPSC sender used to send the message to the discord sink
-    fn new(envelope: Envelope, sink: Arc<Mutex<WebhookSender<T>>>) -> Self {
+    /// * `client` - HTTP client carrying any extra headers
+    /// * `request_headers` - HTTP headers sent with every request
+    fn new(client: Arc<reqwest::blocking::Client>, request_headers: HeaderMap) -> Self {
+        let http = serenity::http::client::Http::new(client, "");
         Self {
-            envelope,
-            body: Vec::new(),
-            sink,
+            http: Arc::new(http),
+            request_headers,
         }
     }
-}
 
-impl<T> Mail for DiscordMailSink<T>
-where
-    T: MailToDiscord,
-{
-    /// Sends the message to the Discord sink queue
-    fn queue(self) -> QueueResult {
-        // Copy id out of the envelope
-        let id = self.envelope.id.clone();
+    /// Executes a webhook through serenity's ratelimiter, sending the extra headers
+    ///
+    /// # Parameters
+    /// * `target` - the webhook to execute and the thread to post to
+    /// * `payload` - the message to post
+    fn execute(
+        &self,
+        target: &WebhookTarget,
+        payload: &ExecuteWebhook,
+    ) -> Result<Option<Message>, serenity::Error> {
+        let body = serde_json::to_vec(&payload.0)?;
+        let token = target.execute_token();
+        let mut request = RequestBuilder::new(RouteInfo::ExecuteWebhook {
+            token: &token,
+            wait: true,
+            webhook_id: target.webhook.id.0,
+        });
+        request
+            .body(Some(&body))
+            .headers(Some(self.request_headers.clone()));
+        let response = self.http.request(request.build())?;
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(response)?))
+    }
 
-        // Return a result based on the result of the send operation
-        // TODO: maybe have a receiver that detects whether there was a failure sending to the
-        // Discord webhook so we can get feedback
-        if let Ok(mut sink) = self.sink.lock() {
-            match sink.send_messsage(self.envelope, self.body) {
-                Ok(_) => QueueResult::QueuedWithId(id),
-                Err(_) => QueueResult::Failed,
+    /// Executes a webhook, retrying rate limits and server errors with exponential backoff
+    ///
+    /// serenity already waits out a 429's Retry-After itself, so a rate limit that still gets
+    /// through is backed off like any other transient failure. Other client errors fail at once.
+    ///
+    /// # Parameters
+    /// * `target` - the webhook to execute and the thread to post to
+    /// * `payload` - the message to post
+    /// * `attachment` - file uploaded along with the message, if any
+    /// * `retry` - the number of retries and the delays between them
+    fn execute_with_retries(
+        &self,
+        target: &WebhookTarget,
+        payload: &ExecuteWebhook,
+        attachment: Option<&Attachment>,
+        retry: &RetryPolicy,
+    ) -> Result<Option<Message>, serenity::Error> {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let result = match attachment {
+                Some(attachment) => self.execute_with_attachment(target, payload, attachment),
+                None => self.execute(target, payload),
+            };
+            let delay = match &result {
+                Err(e) if is_transient(e) => retry.delay(attempt, start.elapsed()),
+                _ => None,
+            };
+            match (result, delay) {
+                (Err(e), Some(delay)) => {
+                    warn!("Webhook send failed, retrying in {:?}: {:?}", delay, e);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                (result, _) => return result,
             }
-        } else {
-            QueueResult::Failed
         }
     }
+
+    /// Executes a webhook with a file attached
+    ///
+    /// serenity can only upload files to channels with a bot token, so the multipart body is built
+    /// here. It is still sent through serenity's ratelimiter, which waits out any Retry-After.
+    ///
+    /// # Parameters
+    /// * `target` - the webhook to execute and the thread to post to
+    /// * `payload` - the message to post
+    /// * `attachment` - the file to upload
+    fn execute_with_attachment(
+        &self,
+        target: &WebhookTarget,
+        payload: &ExecuteWebhook,
+        attachment: &Attachment,
+    ) -> Result<Option<Message>, serenity::Error> {
+        let (body, boundary) = attachment.multipart_body(&serde_json::to_string(&payload.0)?);
+        let mut headers = self.request_headers.clone();
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(&content_type).map_err(HttpError::InvalidHeader)?,
+        );
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        let token = target.execute_token();
+        let mut request = RequestBuilder::new(RouteInfo::ExecuteWebhook {
+            token: &token,
+            wait: true,
+            webhook_id: target.webhook.id.0,
+        });
+        request.body(Some(&body)).headers(Some(headers));
+        let response = self.http.request(request.build())?;
+        Ok(Some(serde_json::from_reader(response)?))
+    }
 }
 
-impl<T> Sink for DiscordMailSink<T> {
-    /// Type fed into the sink
-    type SinkItem = Bytes;
-    /// Error that occurs if sending or polling fails
-    type SinkError = io::Error;
+/// How transient send failures are retried
+///
+/// Each retry waits twice as long as the one before, and no retry starts once the total time
+/// since the first attempt would pass the limit, so a long outage can't hold a client forever.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Number of times a transient send failure is retried
+    attempts: u32,
+    /// Delay before the first retry, doubling for each one after
+    base: Duration,
+    /// Time after the first attempt beyond which no retry is made
+    max_total: Duration,
+}
 
-    /// Adds bytes to the mail body buffer
+impl RetryPolicy {
+    /// Constructor
     ///
     /// # Parameters
-    /// * `item` - Bytes to feed into the buffer
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        // Consume the email bytes
-        self.body.extend_from_slice(&item);
-        // Return that the sink is ready for more
-        Ok(AsyncSink::Ready)
+    /// * `attempts` - number of retries before giving up
+    /// * `base` - delay before the first retry, doubling for each one after
+    /// * `max_total` - time after the first attempt beyond which no retry is made
+    pub fn new(attempts: u32, base: Duration, max_total: Duration) -> Self {
+        Self {
+            attempts,
+            base,
+            max_total,
+        }
     }
 
-    /// Indicates that the poll is complete
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        // Shrink the internal buffer
-        self.body.shrink_to_fit();
-        // We are ready because none of this is actually asynchronous
-        Ok(Async::Ready(()))
+    /// Returns the delay before a retry, or None if the send should give up
+    ///
+    /// # Parameters
+    /// * `attempt` - the number of retries already made
+    /// * `elapsed` - the time since the first attempt
+    fn delay(&self, attempt: u32, elapsed: Duration) -> Option<Duration> {
+        if attempt >= self.attempts {
+            return None;
+        }
+        let delay = 2u32
+            .checked_pow(attempt)
+            .and_then(|factor| self.base.checked_mul(factor))?;
+        elapsed
+            .checked_add(delay)
+            .filter(|&end| end <= self.max_total)
+            .map(|_| delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_RETRY_ATTEMPTS,
+            DEFAULT_RETRY_BASE,
+            DEFAULT_RETRY_MAX_TOTAL,
+        )
+    }
+}
+
+/// Discord's limit on the length of a thread name
+const THREAD_NAME_MAX: usize = 100;
+
+/// The mail conversation a message belongs to, when each conversation gets its own thread
+struct Conversation {
+    /// The mail's Message-ID, which replies refer to, if it has one
+    message_id: Option<String>,
+    /// Name of the thread started for a new conversation
+    thread_name: String,
+}
+
+/// A message as laid out for some webhooks
+struct Post {
+    /// The webhooks the message is posted to
+    targets: Vec<WebhookTarget>,
+    /// The message, in as many parts as it was split into
+    payloads: Vec<ExecuteWebhook>,
+    /// Raw mail uploaded along with the message, if any
+    attachment: Option<Attachment>,
+}
+
+impl Post {
+    /// Lists the parts posted to each webhook, with the attachment uploaded only with the first
+    fn parts(&self) -> impl Iterator<Item = (&ExecuteWebhook, Option<&Attachment>)> {
+        self.payloads.iter().enumerate().map(move |(i, payload)| {
+            let attachment = if i == 0 {
+                self.attachment.as_ref()
+            } else {
+                None
+            };
+            (payload, attachment)
+        })
+    }
+}
+
+/// A message ready to post, sent once the sender's lock is released
+struct Delivery {
+    /// Connection to Discord
+    client: WebhookClient,
+    /// How transient send failures are retried
+    retry: RetryPolicy,
+    /// The message as laid out for each group of webhooks it is posted to
+    posts: Vec<Post>,
+    /// The conversation the message belongs to, if conversations get their own threads
+    conversation: Option<Conversation>,
+}
+
+impl Delivery {
+    /// Posts the message to every target webhook, returning the threads it was posted to
+    ///
+    /// Every webhook is tried even if one fails, and the first failure is returned. A message
+    /// split into parts is posted in order, and a part that fails stops the rest. A new
+    /// conversation's first part starts a thread, which the rest of the parts follow into.
+    fn send(&self) -> Result<Vec<WebhookTarget>, serenity::Error> {
+        let mut result = Ok(Vec::new());
+        for (post, target) in self
+            .posts
+            .iter()
+            .flat_map(|post| post.targets.iter().map(move |target| (post, target)))
+        {
+            let mut target = target.clone();
+            let thread_name = match &self.conversation {
+                Some(conversation) if target.thread_id.is_none() => {
+                    Some(conversation.thread_name.as_str())
+                }
+                _ => None,
+            };
+            let mut sent = Ok(());
+            for (i, (payload, attachment)) in post.parts().enumerate() {
+                let mut payload = payload.clone();
+                if let (0, Some(thread_name)) = (i, thread_name) {
+                    payload.0.insert("thread_name", json!(thread_name));
+                }
+                target.restyle(&mut payload);
+                match self
+                    .client
+                    .execute_with_retries(&target, &payload, attachment, &self.retry)
+                {
+                    // A thread's messages are posted in its own channel
+                    Ok(Some(message)) if i == 0 && thread_name.is_some() => {
+                        target.thread_id = Some(message.channel_id.0)
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        sent = Err(e);
+                        break;
+                    }
+                }
+            }
+            match (sent, &mut result) {
+                (Ok(()), Ok(threads)) if target.thread_id.is_some() => threads.push(target),
+                (Err(e), Ok(_)) => result = Err(e),
+                _ => (),
+            }
+        }
+        result
+    }
+}
+
+/// Sets the color of every embed in a payload
+///
+/// # Parameters
+/// * `webhook_builder` - the payload built by the mail handler
+/// * `color` - the embed color as 0xRRGGBB
+fn set_embed_color(webhook_builder: &mut ExecuteWebhook, color: u32) {
+    if let Some(Value::Array(embeds)) = webhook_builder.0.get_mut("embeds") {
+        for embed in embeds {
+            embed["color"] = json!(color);
+        }
+    }
+}
+
+/// Checks whether a failed request is worth retrying
+///
+/// # Parameters
+/// * `error` - the error the request failed with
+fn is_transient(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(http_error) => match http_error.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code == StatusCode::TOO_MANY_REQUESTS
+                    || response.status_code.is_server_error()
+            }
+            // The request didn't get a response at all, such as on a dropped connection
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+impl<T> WebhookSender<T> {
+    /// Returns the default webhook and thread as a target
+    fn default_target(&self) -> WebhookTarget {
+        WebhookTarget {
+            webhook: self.webhook.clone(),
+            thread_id: self.thread_id,
+            color: None,
+            handler: None,
+        }
+    }
+
+    /// Posts a plain text notice, bypassing the mail handler
+    ///
+    /// # Parameters
+    /// * `content` - the message content
+    fn send_notice(&self, content: &str) -> Result<Option<Message>, serenity::Error> {
+        let mut payload = ExecuteWebhook::default();
+        payload.content(content);
+        self.client.execute(&self.default_target(), &payload)
+    }
+
+    /// Carries out a command sent by mail
+    ///
+    /// # Parameters
+    /// * `action` - the command's action
+    /// * `paused` - whether all mail is being turned away
+    fn run_command(
+        &self,
+        action: CommandAction,
+        paused: &AtomicBool,
+    ) -> Result<Option<Message>, serenity::Error> {
+        match action {
+            CommandAction::Ping => self.send_notice("Bridge is up"),
+            CommandAction::Mute => {
+                let now_paused = !paused.fetch_xor(true, Ordering::SeqCst);
+                if now_paused {
+                    warn!("Muted by command, rejecting all mail");
+                    self.send_notice("Bridge muted, mail is being deferred")
+                } else {
+                    info!("Unmuted by command, accepting mail");
+                    self.send_notice("Bridge unmuted, accepting mail")
+                }
+            }
+            CommandAction::Stats => self.send_notice(&format!(
+                "Sent {} messages, {} failed",
+                self.sent, self.failed
+            )),
+        }
+    }
+
+    /// Counts a message against the post limit, reporting messages dropped over it
+    ///
+    /// Returns whether the message may be posted.
+    fn within_post_limit(&mut self) -> bool {
+        let post_limit = match &mut self.post_limit {
+            Some(post_limit) => post_limit,
+            None => return true,
+        };
+        let (allowed, suppressed) = post_limit.check();
+        if let Some(suppressed) = suppressed {
+            warn!("Suppressed {} messages over the post limit", suppressed);
+            if let Err(e) = self.send_notice(&format!("{} messages suppressed", suppressed)) {
+                self.error_log
+                    .error(&format!("Failed to post suppressed message count: {:?}", e));
+            }
+        }
+        allowed
+    }
+
+    /// Forgets a Message-ID reserved for a message that wasn't posted, so a retry isn't dropped
+    ///
+    /// # Parameters
+    /// * `message_id` - the message's dedup key, if it has a Message-ID
+    fn release_message_id(&mut self, message_id: Option<&str>) {
+        if let (Some(message_ids), Some(message_id)) = (&mut self.message_ids, message_id) {
+            message_ids.release(message_id);
+        }
+    }
+
+    /// Checks that the webhook still exists, without posting anything
+    fn check(&self) -> Result<(), serenity::Error> {
+        get_webhook(
+            &self.client.http,
+            &self.client.request_headers,
+            self.webhook.id.0,
+            &self.webhook.token,
+        )
+        .map(|_| ())
+    }
+
+    /// Fetches the webhook again, replacing the cached copy and logging any changes
+    fn refresh(&mut self) -> Result<(), serenity::Error> {
+        let webhook = get_webhook(
+            &self.client.http,
+            &self.client.request_headers,
+            self.webhook.id.0,
+            &self.webhook.token,
+        )?;
+        if webhook.channel_id != self.webhook.channel_id {
+            info!(
+                "Discord webhook moved from channel {} to {}",
+                self.webhook.channel_id, webhook.channel_id
+            );
+        }
+        if webhook.name != self.webhook.name {
+            info!(
+                "Discord webhook renamed from {:?} to {:?}",
+                self.webhook.name, webhook.name
+            );
+        }
+        self.webhook = webhook;
+        Ok(())
+    }
+}
+
+/// Returns the key a message is remembered by for dedup
+///
+/// Message-IDs are only meant to be unique per sending system, and some reuse fixed IDs, so the
+/// key pairs the Message-ID with the normalized envelope sender. Normalizing keeps a list that
+/// tags each delivery's bounce address from dodging the dedup.
+///
+/// # Parameters
+/// * `normalization` - how the envelope sender is normalized
+/// * `mail` - the MAIL FROM command, if one was given
+/// * `message_id` - the Message-ID header value
+fn dedup_key(
+    normalization: &SenderNormalization,
+    mail: Option<&SmtpMail>,
+    message_id: &str,
+) -> String {
+    let sender = mail
+        .map(|mail| normalization.normalize(&SmtpMail::from(mail).to_string()))
+        .unwrap_or_default();
+    format!("{} {}", sender, message_id.trim())
+}
+
+/// Returns the headers sent with every request, identifying the bridge unless told otherwise
+///
+/// # Parameters
+/// * `headers` - configured extra HTTP headers, which may replace the User-Agent
+fn request_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(USER_AGENT, HeaderValue::from_static(DEFAULT_USER_AGENT));
+    request_headers.extend(headers.clone());
+    request_headers
+}
+
+/// Fetches a webhook through serenity's ratelimiter, sending the extra headers
+///
+/// # Parameters
+/// * `http` - serenity HTTP client
+/// * `request_headers` - HTTP headers sent with every request
+/// * `id` - the webhook id
+/// * `token` - the webhook token
+fn get_webhook(
+    http: &serenity::http::client::Http,
+    request_headers: &HeaderMap,
+    id: u64,
+    token: &str,
+) -> Result<Webhook, serenity::Error> {
+    let mut request = RequestBuilder::new(RouteInfo::GetWebhookWithToken {
+        token,
+        webhook_id: id,
+    });
+    request.headers(Some(request_headers.clone()));
+    http.fire(request.build())
+}
+
+/// Checks whether a webhook payload has nothing Discord would display
+///
+/// # Parameters
+/// * `webhook_builder` - the payload built by the mail handler
+fn is_empty_payload(webhook_builder: &ExecuteWebhook) -> bool {
+    let has_content = match webhook_builder.0.get("content") {
+        Some(Value::String(content)) => !content.trim().is_empty(),
+        Some(_) => true,
+        None => false,
+    };
+    let has_embeds = match webhook_builder.0.get("embeds") {
+        Some(Value::Array(embeds)) => !embeds.is_empty(),
+        Some(_) => true,
+        None => false,
+    };
+    !has_content && !has_embeds
+}
+
+/// Adds a field showing how long the SMTP transaction took and how big the message was
+///
+/// # Parameters
+/// * `webhook_builder` - the payload built by the mail handler
+/// * `timing` - time from connection to queueing
+/// * `size` - the message size in bytes
+fn add_timing_field(webhook_builder: &mut ExecuteWebhook, timing: Duration, size: usize) {
+    let field = json!({
+        "name": "Transaction",
+        "value": format!("{} for {}", handler::format_duration(timing), handler::format_size(size)),
+        "inline": true,
+    });
+    add_field(webhook_builder, field);
+}
+
+/// Adds a field to the first embed, if it has room for one
+///
+/// # Parameters
+/// * `webhook_builder` - the payload built by the mail handler
+/// * `field` - the field's JSON
+fn add_field(webhook_builder: &mut ExecuteWebhook, field: Value) {
+    if let Some(Value::Array(embeds)) = webhook_builder.0.get_mut("embeds") {
+        if let Some(Value::Array(fields)) = embeds.first_mut().and_then(|e| e.get_mut("fields")) {
+            if fields.len() < handler::EMBED_MAX_FIELDS {
+                fields.push(field);
+            }
+        }
+    }
+}
+
+/// Drops embed fields from the end, then cuts descriptions, until the embeds fit a size limit
+///
+/// The body's fields come last, so they are the first to go. A note field says how many
+/// were left out. This is a safety net above the per-field limits, which don't bound the total.
+///
+/// # Parameters
+/// * `webhook_builder` - the payload built by the mail handler
+/// * `max_chars` - the maximum combined length of the embeds' text
+fn fit_embeds(webhook_builder: &mut ExecuteWebhook, max_chars: usize) {
+    let mut total = payload_chars(webhook_builder);
+    if total <= max_chars {
+        return;
+    }
+    let embeds = match webhook_builder.0.get_mut("embeds") {
+        Some(Value::Array(embeds)) => embeds,
+        _ => return,
+    };
+    // Room kept for the note, which is well under this
+    const NOTE_RESERVE: usize = 64;
+    let mut dropped = 0;
+    let mut note_embed = None;
+    for (i, embed) in embeds.iter_mut().enumerate().rev() {
+        if let Some(Value::Array(fields)) = embed.get_mut("fields") {
+            while total + NOTE_RESERVE > max_chars {
+                match fields.pop() {
+                    Some(field) => {
+                        total -= field_chars(&field);
+                        dropped += 1;
+                        note_embed = Some(i);
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    if let Some(i) = note_embed {
+        let note = json!({
+            "name": "Truncated",
+            "value": format!("{} fields left out to fit Discord's size limit", dropped),
+            "inline": false,
+        });
+        total += field_chars(&note);
+        if let Some(Value::Array(fields)) = embeds[i].get_mut("fields") {
+            fields.push(note);
+        }
+    }
+    // Cut descriptions if dropping fields wasn't enough
+    for embed in embeds.iter_mut() {
+        if total <= max_chars {
+            break;
+        }
+        if let Some(Value::String(description)) = embed.get_mut("description") {
+            let len = description.chars().count();
+            let keep = len.saturating_sub(total - max_chars);
+            *description = handler::truncate(description, keep);
+            total = total - len + description.chars().count();
+        }
+    }
+    warn!(
+        "Message too long for Discord, left out {} fields to fit {} characters",
+        dropped, max_chars
+    );
+}
+
+/// Leaves out the parts of a split message past a limit, noting how many in the last one kept
+///
+/// # Parameters
+/// * `payloads` - the parts of the message
+/// * `max_parts` - the most parts posted, at least one
+fn cap_parts(payloads: &mut Vec<ExecuteWebhook>, max_parts: usize) {
+    let max_parts = max_parts.max(1);
+    if payloads.len() <= max_parts {
+        return;
+    }
+    let dropped = payloads.len() - max_parts;
+    warn!(
+        "Message too long for {} parts, left out the last {}",
+        max_parts, dropped
+    );
+    payloads.truncate(max_parts);
+    let last = payloads.last_mut().expect("at least one part is kept");
+    let note = format!(
+        "… {} more messages left out to keep the channel readable",
+        dropped
+    );
+    // Content doesn't count towards the embeds' limit, so the note can't push them over it
+    let content = match last.0.get("content").and_then(Value::as_str) {
+        Some(content) => format!("{}\n{}", content, note),
+        None => note,
+    };
+    last.content(handler::truncate(&content, handler::MESSAGE_CONTENT_MAX));
+}
+
+/// Splits a payload whose embeds are too long for one message into continuation messages
+///
+/// Embeds are packed into messages in order. An embed whose fields don't fit, or that has too
+/// many of them, carries on in a continuation embed with the same title. The first message keeps
+/// the content, and every message keeps the rest of the payload, such as the username.
+///
+/// # Parameters
+/// * `payload` - the payload built by the mail handler
+/// * `max_chars` - the maximum combined length of a message's embeds
+fn split_payload(mut payload: ExecuteWebhook, max_chars: usize) -> Vec<ExecuteWebhook> {
+    let embeds = match payload.0.remove("embeds") {
+        Some(Value::Array(embeds)) => embeds,
+        embeds => {
+            if let Some(embeds) = embeds {
+                payload.0.insert("embeds", embeds);
+            }
+            return vec![payload];
+        }
+    };
+    let mut messages: Vec<Vec<Value>> = vec![Vec::new()];
+    let mut total = 0;
+    for mut embed in embeds {
+        let fields = match embed.get_mut("fields").map(Value::take) {
+            Some(Value::Array(fields)) => fields,
+            _ => Vec::new(),
+        };
+        let title = embed["title"].as_str().unwrap_or(handler::DEFAULT_TITLE);
+        let mut continuation = json!({
+            "title": handler::continued_title(title),
+            "fields": [],
+        });
+        if let Some(color) = embed.get("color") {
+            continuation["color"] = color.clone();
+        }
+        embed["fields"] = json!([]);
+        push_embed(&mut messages, &mut total, embed, 0, max_chars);
+        for field in fields {
+            let chars = field_chars(&field);
+            let full = last_embed(&mut messages)["fields"]
+                .as_array()
+                .map_or(0, Vec::len)
+                >= handler::EMBED_MAX_FIELDS;
+            if full || total + chars > max_chars {
+                // The continuation is only useful with the field in it, so both must fit
+                push_embed(
+                    &mut messages,
+                    &mut total,
+                    continuation.clone(),
+                    chars,
+                    max_chars,
+                );
+            }
+            if let Some(Value::Array(fields)) = last_embed(&mut messages).get_mut("fields") {
+                fields.push(field);
+            }
+            total += chars;
+        }
+    }
+    if messages.len() > 1 {
+        info!(
+            "Message too long for Discord, posting it in {} parts",
+            messages.len()
+        );
+    }
+    let mut continuation = payload.clone();
+    continuation.0.remove("content");
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(i, embeds)| {
+            let mut message = if i == 0 {
+                payload.clone()
+            } else {
+                continuation.clone()
+            };
+            message.embeds(embeds);
+            message
+        })
+        .collect()
+}
+
+/// Adds an embed to the last message, starting a new message if it doesn't fit
+///
+/// # Parameters
+/// * `messages` - the embeds of each message so far
+/// * `total` - the combined length of the last message's embeds
+/// * `embed` - the embed to add
+/// * `needed` - the length of the text about to be added to the embed
+/// * `max_chars` - the maximum combined length of a message's embeds
+fn push_embed(
+    messages: &mut Vec<Vec<Value>>,
+    total: &mut usize,
+    embed: Value,
+    needed: usize,
+    max_chars: usize,
+) {
+    let chars = embed_chars(&embed);
+    let message = messages.last().expect("there is always a message");
+    if !message.is_empty()
+        && (message.len() >= handler::MESSAGE_MAX_EMBEDS || *total + chars + needed > max_chars)
+    {
+        messages.push(Vec::new());
+        *total = 0;
+    }
+    *total += chars;
+    messages
+        .last_mut()
+        .expect("there is always a message")
+        .push(embed);
+}
+
+/// Returns the embed most recently added to the last message
+///
+/// # Parameters
+/// * `messages` - the embeds of each message so far
+fn last_embed(messages: &mut [Vec<Value>]) -> &mut Value {
+    messages
+        .last_mut()
+        .and_then(|message| message.last_mut())
+        .expect("an embed was pushed")
+}
+
+/// Counts the characters in a payload's embeds that count towards Discord's total limit
+///
+/// # Parameters
+/// * `webhook_builder` - the payload built by the mail handler
+fn payload_chars(webhook_builder: &ExecuteWebhook) -> usize {
+    match webhook_builder.0.get("embeds") {
+        Some(Value::Array(embeds)) => embeds.iter().map(embed_chars).sum(),
+        _ => 0,
+    }
+}
+
+/// Counts the characters in an embed that count towards Discord's total limit
+///
+/// # Parameters
+/// * `embed` - the embed's JSON
+fn embed_chars(embed: &Value) -> usize {
+    let text = |value: &Value| value.as_str().map_or(0, |s| s.chars().count());
+    let fields = match embed.get("fields") {
+        Some(Value::Array(fields)) => fields.iter().map(field_chars).sum(),
+        _ => 0,
+    };
+    text(&embed["title"])
+        + text(&embed["description"])
+        + text(&embed["footer"]["text"])
+        + text(&embed["author"]["name"])
+        + fields
+}
+
+/// Counts the characters in an embed field's name and value
+///
+/// # Parameters
+/// * `field` - the field's JSON
+fn field_chars(field: &Value) -> usize {
+    let text = |value: &Value| value.as_str().map_or(0, |s| s.chars().count());
+    text(&field["name"]) + text(&field["value"])
+}
+
+/// Builder constructor for the Discord mailer
+#[derive(Default)]
+pub struct DiscordMailerBuilder {
+    name: Option<String>,
+    empty_message: Option<String>,
+    line_endings: Option<LineEndings>,
+    error_log: Option<ThrottledLogger>,
+    http_headers: HeaderMap,
+    ping_interval: Option<Duration>,
+    pause_file: Option<PathBuf>,
+    served_domains: ServedDomains,
+    max_messages_per_connection: Option<usize>,
+    commands: CommandRecipients,
+    message_id_ttl: Option<Duration>,
+    conversation_ttl: Option<Duration>,
+    data_rate: Option<DataRateMonitor>,
+    data_idle: Option<DataIdleWatch>,
+    startup_notice: bool,
+    refresh_interval: Option<Duration>,
+    spam_check: Option<SpamCheck>,
+    max_embed_chars: Option<usize>,
+    split_messages: bool,
+    max_split_messages: Option<usize>,
+    show_timing: bool,
+    required_headers: Vec<String>,
+    post_limit: Option<PostLimiter>,
+    routes: Routes<RouteDestination>,
+    routing_default: RoutingDefault,
+    allowed_senders: Option<AllowedSenders>,
+    sender_normalization: SenderNormalization,
+    max_body_bytes: Option<usize>,
+    attach_raw_above: Option<usize>,
+    zip_attachments: bool,
+    retries: Option<RetryPolicy>,
+}
+
+impl DiscordMailerBuilder {
+    /// Constructor
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an SMTP service name to the service
+    ///
+    /// # Parameters
+    /// * `name` - the service name
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the message content sent when the handler produces an empty message
+    ///
+    /// # Parameters
+    /// * `empty_message` - the placeholder message content
+    pub fn with_empty_message(mut self, empty_message: &str) -> Self {
+        self.empty_message = Some(empty_message.into());
+        self
+    }
+
+    /// Adds extra HTTP headers sent with every webhook request
+    ///
+    /// # Parameters
+    /// * `http_headers` - the headers to send
+    pub fn with_http_headers(mut self, http_headers: HeaderMap) -> Self {
+        self.http_headers = http_headers;
+        self
+    }
+
+    /// Periodically checks in the background that the webhook still exists
+    ///
+    /// # Parameters
+    /// * `ping_interval` - time between checks
+    pub fn with_ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = Some(ping_interval);
+        self
+    }
+
+    /// Pauses the mailer whenever a control file exists
+    ///
+    /// # Parameters
+    /// * `pause_file` - path of the control file
+    pub fn with_pause_file(mut self, pause_file: PathBuf) -> Self {
+        self.pause_file = Some(pause_file);
+        self
+    }
+
+    /// Only accepts recipients in the given domains
+    ///
+    /// # Parameters
+    /// * `served_domains` - the domains to accept, or an empty list to accept all
+    pub fn with_served_domains(mut self, served_domains: ServedDomains) -> Self {
+        self.served_domains = served_domains;
+        self
+    }
+
+    /// Limits how many messages a client may send on one connection
+    ///
+    /// Past the limit, recipients get a temporary failure so the client reconnects.
+    ///
+    /// # Parameters
+    /// * `max_messages` - the number of messages allowed per connection
+    pub fn with_max_messages_per_connection(mut self, max_messages: usize) -> Self {
+        self.max_messages_per_connection = Some(max_messages);
+        self
+    }
+
+    /// Sets recipient addresses that control the bridge instead of receiving mail
+    ///
+    /// Anyone who can send mail to the bridge can use these, so keep the addresses private.
+    ///
+    /// # Parameters
+    /// * `commands` - actions keyed by recipient address
+    pub fn with_commands(mut self, commands: CommandRecipients) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// Drops messages whose Message-ID was already posted within a time window
+    ///
+    /// # Parameters
+    /// * `ttl` - how long a posted Message-ID is remembered
+    pub fn with_message_id_dedup(mut self, ttl: Duration) -> Self {
+        self.message_id_ttl = Some(ttl);
+        self
+    }
+
+    /// Posts each mail conversation in its own thread, keyed on References and In-Reply-To
+    ///
+    /// A mail that refers to a posted message goes to that message's thread, and any other mail
+    /// starts a thread named after its subject. Discord only lets webhooks start threads in forum
+    /// channels.
+    ///
+    /// # Parameters
+    /// * `ttl` - how long the thread a message was posted to is remembered
+    pub fn with_conversation_threads(mut self, ttl: Duration) -> Self {
+        self.conversation_ttl = Some(ttl);
+        self
+    }
+
+    /// Aborts transactions whose message data arrives below a minimum rate
+    ///
+    /// # Parameters
+    /// * `min_rate` - minimum average rate in bytes per second
+    /// * `window` - time the rate is averaged over
+    pub fn with_min_data_rate(mut self, min_rate: u64, window: Duration) -> Self {
+        self.data_rate = Some(DataRateMonitor::new(min_rate, window));
+        self
+    }
+
+    /// Records when message data arrives, so transfers that stall can be closed
+    ///
+    /// # Parameters
+    /// * `watch` - the transfers being watched, shared with the connection service
+    pub fn with_data_idle_watch(mut self, watch: DataIdleWatch) -> Self {
+        self.data_idle = Some(watch);
+        self
+    }
+
+    /// Posts a notice to Discord once the mailer is built and the webhook checked
+    ///
+    /// # Parameters
+    /// * `startup_notice` - whether to post the notice
+    pub fn with_startup_notice(mut self, startup_notice: bool) -> Self {
+        self.startup_notice = startup_notice;
+        self
+    }
+
+    /// Periodically fetches the webhook again to pick up changes made in Discord
+    ///
+    /// # Parameters
+    /// * `refresh_interval` - time between fetches
+    pub fn with_refresh_interval(mut self, refresh_interval: Duration) -> Self {
+        self.refresh_interval = Some(refresh_interval);
+        self
+    }
+
+    /// Turns away mail that an upstream filter marked as spam
+    ///
+    /// # Parameters
+    /// * `spam_check` - which spam headers are honored and what happens to spam
+    pub fn with_spam_check(mut self, spam_check: SpamCheck) -> Self {
+        self.spam_check = Some(spam_check);
+        self
+    }
+
+    /// Sets the combined length of embed text above which the raw mail is uploaded instead
+    ///
+    /// When the messages are split or the mail is too large to upload, fields are dropped to fit.
+    ///
+    /// # Parameters
+    /// * `max_embed_chars` - the maximum combined length of a message's embeds
+    pub fn with_max_embed_chars(mut self, max_embed_chars: usize) -> Self {
+        self.max_embed_chars = Some(max_embed_chars);
+        self
+    }
+
+    /// Sets whether the time from connection to queueing is shown in the embed
+    ///
+    /// # Parameters
+    /// * `show_timing` - whether to add a transaction field
+    pub fn with_transaction_timing(mut self, show_timing: bool) -> Self {
+        self.show_timing = show_timing;
+        self
+    }
+
+    /// Refuses messages that lack any of the given header fields
+    ///
+    /// # Parameters
+    /// * `required_headers` - names of the header fields a message must have
+    pub fn with_required_headers(mut self, required_headers: Vec<String>) -> Self {
+        self.required_headers = required_headers;
+        self
+    }
+
+    /// Sets whether a message too long for Discord is posted as several messages
+    ///
+    /// Otherwise fields are dropped from the end until it fits.
+    ///
+    /// # Parameters
+    /// * `split_messages` - whether to post continuation messages
+    pub fn with_message_splitting(mut self, split_messages: bool) -> Self {
+        self.split_messages = split_messages;
+        self
+    }
+
+    /// Sets the most messages one mail is posted across when it is split
+    ///
+    /// The parts past it are left out, with a note in the last one posted.
+    ///
+    /// # Parameters
+    /// * `max_split_messages` - the most messages posted for one mail
+    pub fn with_max_split_messages(mut self, max_split_messages: usize) -> Self {
+        self.max_split_messages = Some(max_split_messages);
+        self
+    }
+
+    /// Uploads mail above a size as a file with a short summary, rather than showing the body
+    ///
+    /// # Parameters
+    /// * `attach_raw_above` - the size in bytes above which mail is attached
+    pub fn with_raw_attachment(mut self, attach_raw_above: usize) -> Self {
+        self.attach_raw_above = Some(attach_raw_above);
+        self
+    }
+
+    /// Sets whether uploaded files are bundled into a zip named after the subject
+    ///
+    /// A zip still over Discord's upload limit is left out, as the file would be.
+    ///
+    /// # Parameters
+    /// * `zip_attachments` - whether to zip uploaded files
+    pub fn with_zipped_attachments(mut self, zip_attachments: bool) -> Self {
+        self.zip_attachments = zip_attachments;
+        self
+    }
+
+    /// Sets how transient send failures are retried
+    ///
+    /// # Parameters
+    /// * `retry` - the number of retries and the delays between them
+    pub fn with_retries(mut self, retry: RetryPolicy) -> Self {
+        self.retries = Some(retry);
+        self
+    }
+
+    /// Sets the size above which a message body aborts the transaction
+    ///
+    /// # Parameters
+    /// * `max_body_bytes` - the maximum body size in bytes
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = Some(max_body_bytes);
+        self
+    }
+
+    /// Only accepts mail from the given senders
+    ///
+    /// # Parameters
+    /// * `allowed_senders` - addresses and `@domain` suffixes to allow
+    pub fn with_allowed_senders(mut self, allowed_senders: AllowedSenders) -> Self {
+        self.allowed_senders = Some(allowed_senders);
+        self
+    }
+
+    /// Sets how sender addresses are normalized for the allowed senders and dedup keys
+    ///
+    /// # Parameters
+    /// * `sender_normalization` - the normalization rules
+    pub fn with_sender_normalization(mut self, sender_normalization: SenderNormalization) -> Self {
+        self.sender_normalization = sender_normalization;
+        self
+    }
+
+    /// Posts mail for matching recipients to other webhooks
+    ///
+    /// # Parameters
+    /// * `routes` - recipient patterns mapped to webhook id and auth info
+    pub fn with_routes(mut self, routes: Routes<RouteDestination>) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Sets what happens to mail for recipients no route matches
+    ///
+    /// # Parameters
+    /// * `routing_default` - post to the default webhook, drop the mail, or reject the recipient
+    pub fn with_routing_default(mut self, routing_default: RoutingDefault) -> Self {
+        self.routing_default = routing_default;
+        self
+    }
+
+    /// Caps how many messages are posted per time window, dropping the rest
+    ///
+    /// # Parameters
+    /// * `max_posts` - number of messages allowed in a window
+    /// * `window` - length of each window
+    pub fn with_post_limit(mut self, max_posts: u64, window: Duration) -> Self {
+        self.post_limit = Some(PostLimiter::new(max_posts, window));
+        self
+    }
+
+    /// Sets how often repeated send failures are logged
+    ///
+    /// # Parameters
+    /// * `every` - number of repeats after which a failure is logged again
+    /// * `interval` - time after which a repeated failure is logged again
+    pub fn with_error_log_throttle(mut self, every: u64, interval: Duration) -> Self {
+        self.error_log = Some(ThrottledLogger::new(every, interval));
+        self
+    }
+
+    /// Sets how line endings in the message data are treated
+    ///
+    /// # Parameters
+    /// * `line_endings` - lenient or strict line ending handling
+    pub fn with_line_endings(mut self, line_endings: LineEndings) -> Self {
+        self.line_endings = Some(line_endings);
+        self
+    }
+
+    /// Constructs the Discord mailer, starting any requested background threads
+    ///
+    /// # Parameters
+    /// * `webhook_auth` - Discord webhook id and auth info
+    /// * `handler` - Object used to generate messages from email
+    pub fn build<T>(
+        self,
+        webhook_auth: &DiscordWebhookAuth,
+        handler: T,
+    ) -> Result<DiscordMailer<T>, serenity::Error>
+    where
+        T: Clone + MailToDiscord + Send + 'static,
+    {
+        let webhook_sender = WebhookSender::new(webhook_auth, handler, &self.http_headers)?;
+        self.build_with_sender(webhook_sender)
+    }
+
+    /// Constructs the Discord mailer around a sender whose webhook has already been fetched
+    ///
+    /// # Parameters
+    /// * `webhook_sender` - the sender posting to the default webhook
+    fn build_with_sender<T>(
+        self,
+        mut webhook_sender: WebhookSender<T>,
+    ) -> Result<DiscordMailer<T>, serenity::Error>
+    where
+        T: Clone + MailToDiscord + Send + 'static,
+    {
+        let name = self.name.unwrap_or_else(|| DEFAULT_SERVICE_NAME.into());
+        if let Some(empty_message) = self.empty_message {
+            webhook_sender.empty_message = empty_message;
+        }
+        if let Some(error_log) = self.error_log {
+            webhook_sender.error_log = error_log;
+        }
+        webhook_sender.message_ids = self.message_id_ttl.map(MessageIdCache::new);
+        webhook_sender.conversations = self.conversation_ttl.map(ConversationThreads::new);
+        webhook_sender.sender_normalization = self.sender_normalization.clone();
+        webhook_sender.spam_check = self.spam_check;
+        webhook_sender.required_headers = self.required_headers;
+        webhook_sender.post_limit = self.post_limit;
+        if let Some(retry) = self.retries {
+            webhook_sender.retry = retry;
+        }
+        // Fetch every routed webhook up front, so a bad route fails at startup
+        let http = &webhook_sender.client.http;
+        let request_headers = &webhook_sender.client.request_headers;
+        let routes = Arc::new(self.routes);
+        webhook_sender.routes = routes.as_ref().clone().try_map(|destination| {
+            let auth = &destination.auth;
+            get_webhook(http, request_headers, auth.id, &auth.token).map(|webhook| WebhookTarget {
+                webhook,
+                thread_id: auth.thread_id,
+                color: destination.color,
+                handler: destination.handler.clone(),
+            })
+        })?;
+        if let Some(max_embed_chars) = self.max_embed_chars {
+            webhook_sender.max_embed_chars = max_embed_chars;
+        }
+        webhook_sender.routing_default = self.routing_default;
+        webhook_sender.split_messages = self.split_messages;
+        if let Some(max_split_messages) = self.max_split_messages {
+            webhook_sender.max_split_messages = max_split_messages;
+        }
+        webhook_sender.attach_raw_above = self.attach_raw_above;
+        webhook_sender.zip_attachments = self.zip_attachments;
+        // The webhook was checked when the sender was created, so it is known to be usable here
+        if self.startup_notice {
+            if let Err(e) = webhook_sender.send_notice(&format!("{} started", name)) {
+                warn!("Failed to post startup notice: {:?}", e);
+            }
+        }
+        let mut mailer = DiscordMailer::from_sender(&name, webhook_sender);
+        if let Some(line_endings) = self.line_endings {
+            mailer.line_endings = line_endings;
+        }
+        mailer.served_domains = Arc::new(self.served_domains);
+        mailer.max_messages_per_connection = self.max_messages_per_connection;
+        mailer.commands = Arc::new(self.commands);
+        mailer.data_rate = self.data_rate;
+        mailer.data_idle = self.data_idle;
+        mailer.show_timing = self.show_timing;
+        mailer.allowed_senders = self.allowed_senders.map(Arc::new);
+        mailer.sender_normalization = Arc::new(self.sender_normalization);
+        mailer.routes = routes;
+        mailer.routing_default = self.routing_default;
+        if let Some(max_body_bytes) = self.max_body_bytes {
+            mailer.max_body_bytes = max_body_bytes;
+        }
+        if let Some(ping_interval) = self.ping_interval {
+            mailer.spawn_pinger(ping_interval);
+        }
+        if let Some(refresh_interval) = self.refresh_interval {
+            mailer.spawn_refresher(refresh_interval);
+        }
+        if let Some(pause_file) = self.pause_file {
+            mailer.spawn_pause_watcher(pause_file, PAUSE_FILE_POLL_INTERVAL);
+        }
+        Ok(mailer)
+    }
+}
+
+/// Reads mail data over SMTP and sends it over Discord
+pub struct DiscordMailSink<T> {
+    /// The message's envelope
+    envelope: Envelope,
+    /// Buffer used to store the message body
+    body: Vec<u8>,
+    /// MPSC sender used to send the message to the Discord sink
+    sink: Arc<Mutex<WebhookSender<T>>>,
+    /// How line endings in the message data are treated
+    line_endings: LineEndings,
+    /// Whether the last byte received was a CR, since a CRLF may span two chunks
+    last_was_cr: bool,
+    /// Commands sent by mail to command recipients
+    actions: Vec<CommandAction>,
+    /// Whether all mail is being turned away, toggled by the mute command
+    paused: Arc<AtomicBool>,
+    /// Tracks how fast message data arrives, if a minimum rate is enforced
+    data_rate: Option<DataRateMonitor>,
+    /// When the connection started, if the transaction time is shown
+    connected: Option<Instant>,
+    /// Size above which the body aborts the transaction
+    max_body_bytes: usize,
+    /// Transfers watched for stalls and this one's peer, if stalls are caught
+    data_idle: Option<(DataIdleWatch, SocketAddr)>,
+}
+
+impl<T> DiscordMailSink<T> {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `envelope` - The message's envelope
+    /// * `sink` - MPSC sender used to send the message to the discord sink
+    /// * `line_endings` - How line endings in the message data are treated
+    fn new(
+        envelope: Envelope,
+        sink: Arc<Mutex<WebhookSender<T>>>,
+        line_endings: LineEndings,
+    ) -> Self {
+        Self {
+            envelope,
+            body: Vec::new(),
+            sink,
+            line_endings,
+            last_was_cr: false,
+            actions: Vec::new(),
+            paused: Arc::new(AtomicBool::new(false)),
+            data_rate: None,
+            connected: None,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            data_idle: None,
+        }
+    }
+}
+
+impl<T> Mail for DiscordMailSink<T>
+where
+    T: MailToDiscord,
+{
+    /// Sends the message to the Discord sink queue
+    fn queue(self) -> QueueResult {
+        // Copy id out of the envelope
+        let id = self.envelope.id.clone();
+        // The data is all in, so a slow send to Discord isn't mistaken for a stalled sender
+        if let Some((watch, peer)) = &self.data_idle {
+            watch.end(*peer);
+        }
+
+        // The webhook is executed here and waited on, so a failed send is reported to the client
+        let (delivery, message_id) = {
+            let mut sink = match self.sink.lock() {
+                Ok(sink) => sink,
+                Err(_) => return QueueResult::Failed,
+            };
+            for action in self.actions {
+                if let Err(e) = sink.run_command(action, &self.paused) {
+                    sink.error_log
+                        .error(&format!("Failed to run {:?} command: {:?}", action, e));
+                }
+            }
+            // Mail only addressed to command recipients isn't posted
+            if self.envelope.rcpts.is_empty() {
+                return QueueResult::QueuedWithId(id);
+            }
+            let (headers, _) = Headers::parse(&self.body);
+            // Refuse mail missing a header the operator requires
+            if let Some(missing) = sink
+                .required_headers
+                .iter()
+                .find(|name| headers.get(name).is_none())
+            {
+                info!("Refusing message {} without a {} header", id, missing);
+                return QueueResult::Refused;
+            }
+            // Honor an upstream spam filter's verdict
+            if let Some(spam_check) = &sink.spam_check {
+                if spam_check.is_spam(&headers) {
+                    info!("Message {} was marked as spam upstream", id);
+                    return match spam_check.action {
+                        SpamAction::Reject => QueueResult::Refused,
+                        SpamAction::Drop => QueueResult::QueuedWithId(id),
+                    };
+                }
+            }
+            // Accept mail for recipients no route matches without posting it, if asked to
+            if sink.targets(&self.envelope.rcpts).is_empty() {
+                info!("Dropping message {} with no routed recipients", id);
+                return QueueResult::QueuedWithId(id);
+            }
+            // Drop repeat deliveries of a message that was already posted or is being posted
+            let mail = self.envelope.mail.as_ref();
+            let message_id = headers
+                .get("Message-ID")
+                .map(|message_id| dedup_key(&sink.sender_normalization, mail, message_id));
+            if let (Some(message_ids), Some(message_id)) = (&mut sink.message_ids, &message_id) {
+                // Reserved before the lock is released, so a repeat sent meanwhile is caught
+                if !message_ids.reserve(message_id) {
+                    info!("Dropping duplicate of message {}", message_id);
+                    return QueueResult::QueuedWithId(id);
+                }
+            }
+            // Keep a mail storm from flooding the channel
+            if !sink.within_post_limit() {
+                info!("Dropping message {} over the post limit", id);
+                sink.release_message_id(message_id.as_deref());
+                return QueueResult::QueuedWithId(id);
+            }
+            let timing = self.connected.map(|connected| connected.elapsed());
+            (sink.prepare(self.envelope, self.body, timing), message_id)
+        };
+        // Post without the lock, so retries don't hold up every other connection's mail
+        let result = delivery.send();
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(_) => return QueueResult::Failed,
+        };
+        match result {
+            Ok(threads) => {
+                // Replies to this message follow it into the threads it was posted to
+                let posted_id = delivery
+                    .conversation
+                    .as_ref()
+                    .and_then(|conversation| conversation.message_id.as_ref());
+                if let (Some(conversations), Some(posted_id)) = (&mut sink.conversations, posted_id)
+                {
+                    for target in threads {
+                        if let Some(thread_id) = target.thread_id {
+                            conversations.record(target.webhook.id.0, posted_id, thread_id);
+                        }
+                    }
+                }
+                sink.sent += 1;
+                QueueResult::QueuedWithId(id)
+            }
+            Err(e) => {
+                // Only posted messages stay remembered, so a failed one can still be retried
+                sink.release_message_id(message_id.as_deref());
+                sink.failed += 1;
+                sink.error_log
+                    .error(&format!("Failed to send mail to Discord: {:?}", e));
+                QueueResult::Failed
+            }
+        }
+    }
+}
+
+impl<T> Sink for DiscordMailSink<T> {
+    /// Type fed into the sink
+    type SinkItem = Bytes;
+    /// Error that occurs if sending or polling fails
+    type SinkError = io::Error;
+
+    /// Adds bytes to the mail body buffer
+    ///
+    /// The data arrives already un-stuffed. samotop's decoder splits the escaping dot off lines
+    /// starting with `..` for its session to drop, and consumes the terminating `.` line itself,
+    /// so neither reaches here.
+    ///
+    /// # Parameters
+    /// * `item` - Bytes to feed into the buffer
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // Drop senders that trickle data in to tie up the connection
+        if let Some(data_rate) = &mut self.data_rate {
+            data_rate.record(item.len())?;
+        }
+        if let Some((watch, peer)) = &self.data_idle {
+            watch.record(*peer);
+        }
+        // Keep a huge message from exhausting memory, counting the CRs lenient endings add
+        let added = match self.line_endings {
+            LineEndings::Lenient => item.len() + bare_lf_count(&item, self.last_was_cr),
+            LineEndings::Strict => item.len(),
+        };
+        if self.body.len() + added > self.max_body_bytes {
+            return Err(io::Error::other(format!(
+                "message body exceeds the maximum of {} bytes",
+                self.max_body_bytes
+            )));
+        }
+        // Consume the email bytes, making sure every line ends in CRLF
+        for &byte in item.iter() {
+            if byte == b'\n' && !self.last_was_cr {
+                match self.line_endings {
+                    LineEndings::Lenient => self.body.push(b'\r'),
+                    LineEndings::Strict => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "bare LF in message data",
+                        ))
+                    }
+                }
+            }
+            self.body.push(byte);
+            self.last_was_cr = byte == b'\r';
+        }
+        // Return that the sink is ready for more
+        Ok(AsyncSink::Ready)
+    }
+
+    /// Indicates that the poll is complete
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        // Shrink the internal buffer
+        self.body.shrink_to_fit();
+        // We are ready because none of this is actually asynchronous
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Counts the LFs in a chunk of message data that aren't preceded by a CR
+///
+/// # Parameters
+/// * `data` - the chunk of message data
+/// * `last_was_cr` - whether the previous chunk ended in a CR
+fn bare_lf_count(data: &[u8], last_was_cr: bool) -> usize {
+    let mut previous_was_cr = last_was_cr;
+    data.iter()
+        .filter(|&&byte| {
+            let bare = byte == b'\n' && !previous_was_cr;
+            previous_was_cr = byte == b'\r';
+            bare
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::EmbedHandler;
+    use bytes::BytesMut;
+    use futures::Future;
+    use samotop::model::command::{SmtpAddress, SmtpHost};
+    use samotop::model::controll::{ClientControll, ServerControll};
+    use samotop::protocol::SmtpCodec;
+    use serenity::http::error::ErrorResponse;
+    use tokio::codec::Encoder;
+
+    /// Handler that leaves the payload empty
+    #[derive(Clone)]
+    struct NoopHandler;
+
+    impl MailToDiscord for NoopHandler {
+        fn handle(&mut self, _: &Envelope, _: &[u8], _: &mut ExecuteWebhook) {}
+    }
+
+    /// Builds a sender around a made-up webhook, without contacting Discord
+    ///
+    /// # Parameters
+    /// * `handler` - Object that converts mail to Discord webhook messages
+    fn test_sender<T: MailToDiscord>(handler: T) -> WebhookSender<T> {
+        let client = Arc::new(reqwest::blocking::Client::new());
+        WebhookSender::from_webhook(client, HeaderMap::new(), test_webhook(1), handler)
+    }
+
+    /// Builds a made-up webhook
+    ///
+    /// # Parameters
+    /// * `id` - the webhook id
+    fn test_webhook(id: u64) -> Webhook {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "channel_id": "2",
+            "guild_id": null,
+            "avatar": null,
+            "name": "test",
+            "token": "token",
+            "user": null,
+        }))
+        .expect("valid webhook")
+    }
+
+    /// Builds a request to accept a recipient
+    ///
+    /// # Parameters
+    /// * `rcpt` - the recipient path
+    fn test_request(rcpt: SmtpPath) -> AcceptRecipientRequest {
+        AcceptRecipientRequest {
+            name: DEFAULT_SERVICE_NAME.into(),
+            local: None,
+            peer: None,
+            helo: None,
+            mail: Some(SmtpMail::Mail(test_path("sender", "example.com"))),
+            id: "id".into(),
+            rcpt,
+        }
+    }
+
+    /// Returns whether the mailer accepts a recipient
+    ///
+    /// # Parameters
+    /// * `mailer` - the mailer to ask
+    /// * `rcpt` - the recipient path
+    fn accepts<T>(mailer: &DiscordMailer<T>, rcpt: SmtpPath) -> bool {
+        match mailer.accept(test_request(rcpt)).wait() {
+            Ok(AcceptRecipientResult::Accepted(_)) => true,
+            Ok(_) => false,
+            Err(e) => panic!("accept failed: {}", e),
+        }
+    }
+
+    /// Builds a sender with alerts@example.com routed to webhook 3
+    ///
+    /// # Parameters
+    /// * `routing_default` - what happens to mail for other recipients
+    fn routed_sender(routing_default: RoutingDefault) -> WebhookSender<NoopHandler> {
+        let mut sender = test_sender(NoopHandler);
+        sender.routes = Routes::new(vec![(
+            "alerts@example.com".into(),
+            WebhookTarget {
+                webhook: test_webhook(3),
+                thread_id: None,
+                color: None,
+                handler: None,
+            },
+        )]);
+        sender.routing_default = routing_default;
+        sender
+    }
+
+    /// Returns the ids of the webhooks mail for some recipients is posted to
+    ///
+    /// # Parameters
+    /// * `sender` - the sender resolving the routes
+    /// * `rcpts` - the recipient paths
+    fn target_ids<T: MailToDiscord>(sender: &WebhookSender<T>, rcpts: &[SmtpPath]) -> Vec<u64> {
+        sender
+            .targets(rcpts)
+            .iter()
+            .map(|target| target.webhook.id.0)
+            .collect()
+    }
+
+    #[test]
+    fn thread_is_passed_on_execute() {
+        let target = WebhookTarget {
+            webhook: test_webhook(1),
+            thread_id: Some(5),
+            color: None,
+            handler: None,
+        };
+        let token = target.execute_token();
+        let mut request = RequestBuilder::new(RouteInfo::ExecuteWebhook {
+            token: &token,
+            wait: true,
+            webhook_id: 1,
+        });
+        request.body(Some(b"{}"));
+        let client = reqwest::blocking::Client::new();
+        let request = request.build();
+        let url = request
+            .build(&client, "")
+            .expect("valid route")
+            .build()
+            .expect("valid request")
+            .url()
+            .clone();
+        assert!(url.path().ends_with("/webhooks/1/token"));
+        assert_eq!(url.query(), Some("wait=true&thread_id=5"));
+    }
+
+    #[test]
+    fn threads_of_one_webhook_are_separate_targets() {
+        let mut sender = routed_sender(RoutingDefault::default());
+        sender.thread_id = Some(7);
+        sender.routes = Routes::new(vec![(
+            "alerts@example.com".into(),
+            WebhookTarget {
+                webhook: test_webhook(1),
+                thread_id: Some(8),
+                color: None,
+                handler: None,
+            },
+        )]);
+        let targets = sender.targets(&[
+            test_path("alerts", "example.com"),
+            test_path("other", "example.com"),
+        ]);
+        let threads = targets
+            .iter()
+            .map(|target| target.thread_id)
+            .collect::<Vec<_>>();
+        assert_eq!(threads, vec![Some(8), Some(7)]);
+    }
+
+    #[test]
+    fn reply_is_posted_in_its_conversation_thread() {
+        let mut sender = test_sender(NoopHandler);
+        let mut conversations = ConversationThreads::new(Duration::from_secs(60));
+        conversations.record(1, "<first@example.com>", 10);
+        sender.conversations = Some(conversations);
+        let body = b"Subject: Re: outage\r\nMessage-ID: <second@example.com>\r\n\
+                     In-Reply-To: <first@example.com>\r\n\r\nfixed\r\n"
+            .to_vec();
+        let delivery = sender.prepare(test_envelope(None), body, None);
+        assert_eq!(delivery.posts[0].targets[0].thread_id, Some(10));
+        let conversation = delivery.conversation.expect("conversations are threaded");
+        assert_eq!(
+            conversation.message_id.as_deref(),
+            Some("<second@example.com>")
+        );
+    }
+
+    #[test]
+    fn new_conversation_starts_a_thread() {
+        let mut sender = test_sender(NoopHandler);
+        sender.conversations = Some(ConversationThreads::new(Duration::from_secs(60)));
+        let body = b"Subject: outage\r\nMessage-ID: <first@example.com>\r\n\r\ndown\r\n".to_vec();
+        let delivery = sender.prepare(test_envelope(None), body, None);
+        assert_eq!(delivery.posts[0].targets[0].thread_id, None);
+        let conversation = delivery.conversation.expect("conversations are threaded");
+        assert_eq!(conversation.thread_name, "outage");
+    }
+
+    #[test]
+    fn route_color_replaces_the_handler_color() {
+        let mut sender = test_sender(EmbedHandler::new().with_color(0x111111));
+        sender.routes = Routes::new(vec![(
+            "alerts@example.com".into(),
+            WebhookTarget {
+                webhook: test_webhook(3),
+                thread_id: None,
+                color: Some(0x222222),
+                handler: None,
+            },
+        )]);
+        let mut envelope = test_envelope(None);
+        envelope.rcpts.push(test_path("other", "example.com"));
+        let delivery = sender.prepare(envelope, b"Subject: hi\r\n\r\nbody\r\n".to_vec(), None);
+        let colors = delivery.posts[0]
+            .targets
+            .iter()
+            .map(|target| {
+                let mut payload = delivery.posts[0].payloads[0].clone();
+                target.restyle(&mut payload);
+                payload.0["embeds"][0]["color"].clone()
+            })
+            .collect::<Vec<_>>();
+        // The routed recipient gets the route's color, the other falls back to the handler's
+        assert_eq!(colors, vec![json!(0x222222), json!(0x111111)]);
+    }
+
+    #[test]
+    fn route_format_lays_out_its_own_message() {
+        let mut sender = test_sender(NoopHandler);
+        sender.routes = Routes::new(vec![(
+            "alerts@example.com".into(),
+            WebhookTarget {
+                webhook: test_webhook(3),
+                thread_id: None,
+                color: None,
+                handler: Some(EmbedHandler::new().with_color(0x333333)),
+            },
+        )]);
+        let mut envelope = test_envelope(None);
+        envelope.rcpts.push(test_path("other", "example.com"));
+        let delivery = sender.prepare(envelope, b"Subject: hi\r\n\r\nbody\r\n".to_vec(), None);
+        let posts = delivery
+            .posts
+            .iter()
+            .map(|post| {
+                let ids = post
+                    .targets
+                    .iter()
+                    .map(|target| target.webhook.id.0)
+                    .collect::<Vec<_>>();
+                let color = post.payloads[0]
+                    .0
+                    .get("embeds")
+                    .map(|embeds| embeds[0]["color"].clone());
+                (ids, color)
+            })
+            .collect::<Vec<_>>();
+        // The default webhook gets the sender's handler, the route its own
+        assert_eq!(
+            posts,
+            vec![(vec![1], None), (vec![3], Some(json!(0x333333)))]
+        );
+    }
+
+    #[test]
+    fn unserved_domain_is_rejected() {
+        let mut mailer = DiscordMailer::from_sender("test", test_sender(NoopHandler));
+        mailer.served_domains = Arc::new(ServedDomains::new(vec!["example.com".into()]));
+        assert!(accepts(&mailer, test_path("alerts", "example.com")));
+        let request = test_request(test_path("alerts", "example.org"));
+        assert!(matches!(
+            mailer.accept(request).wait(),
+            Ok(AcceptRecipientResult::Rejected)
+        ));
+    }
+
+    #[test]
+    fn connection_is_deferred_past_the_message_limit() {
+        let mut mailer = DiscordMailer::from_sender("test", test_sender(NoopHandler));
+        mailer.max_messages_per_connection = Some(2);
+        let rcpt = || test_path("alerts", "example.com");
+        for _ in 0..2 {
+            assert!(accepts(&mailer, rcpt()));
+            mailer
+                .mail(test_envelope(None))
+                .wait()
+                .expect("mail started");
+        }
+        let result = mailer.accept(test_request(rcpt())).wait();
+        assert!(matches!(result, Ok(AcceptRecipientResult::Failed)));
+        // The session service clones the mailer for each new connection
+        assert!(accepts(&mailer.clone(), rcpt()));
+    }
+
+    #[test]
+    fn fallback_posts_unrouted_mail_to_the_default_webhook() {
+        let sender = routed_sender(RoutingDefault::Fallback);
+        let routed = test_path("alerts", "example.com");
+        let unrouted = test_path("billing", "example.com");
+        assert_eq!(target_ids(&sender, std::slice::from_ref(&routed)), vec![3]);
+        assert_eq!(
+            target_ids(&sender, std::slice::from_ref(&unrouted)),
+            vec![1]
+        );
+        assert_eq!(target_ids(&sender, &[routed, unrouted]), vec![3, 1]);
+    }
+
+    #[test]
+    fn drop_skips_unrouted_recipients() {
+        let sender = routed_sender(RoutingDefault::Drop);
+        let routed = test_path("alerts", "example.com");
+        let unrouted = test_path("billing", "example.com");
+        assert!(target_ids(&sender, std::slice::from_ref(&unrouted)).is_empty());
+        assert_eq!(target_ids(&sender, &[routed, unrouted]), vec![3]);
+    }
+
+    #[test]
+    fn reject_refuses_unrouted_recipients() {
+        let mut mailer = DiscordMailer::from_sender("test", test_sender(NoopHandler));
+        mailer.routes = Arc::new(Routes::new(vec![(
+            "alerts@example.com".into(),
+            RouteDestination::new(DiscordWebhookAuth::new(3, "token".into())),
+        )]));
+        mailer.routing_default = RoutingDefault::Reject;
+        assert!(accepts(&mailer, test_path("alerts", "example.com")));
+        assert!(!accepts(&mailer, test_path("billing", "example.com")));
+        assert!(accepts(&mailer, SmtpPath::Postmaster));
+        mailer.routing_default = RoutingDefault::Fallback;
+        assert!(accepts(&mailer, test_path("billing", "example.com")));
+    }
+
+    /// Builds an envelope for one recipient
+    ///
+    /// # Parameters
+    /// * `mail` - the MAIL FROM command, if one was given
+    fn test_envelope(mail: Option<SmtpMail>) -> Envelope {
+        Envelope {
+            name: DEFAULT_SERVICE_NAME.into(),
+            local: None,
+            peer: None,
+            helo: None,
+            mail,
+            id: "id".into(),
+            rcpts: vec![test_path("alerts", "example.com")],
+        }
+    }
+
+    /// Builds a mailbox path
+    ///
+    /// # Parameters
+    /// * `local` - the local part
+    /// * `domain` - the domain
+    fn test_path(local: &str, domain: &str) -> SmtpPath {
+        SmtpPath::Direct(SmtpAddress::Mailbox(
+            local.into(),
+            SmtpHost::Domain(domain.into()),
+        ))
+    }
+
+    #[test]
+    fn empty_payload_gets_placeholder() {
+        let mut sender = test_sender(NoopHandler);
+        sender.empty_message = "nothing here".into();
+        let (payloads, attachment) = sender.payloads(&test_envelope(None), &[], None);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].0.get("content"), Some(&json!("nothing here")));
+        assert!(attachment.is_none());
+    }
+
+    #[test]
+    fn attachment_leaves_out_bcc() {
+        let mut sender = test_sender(EmbedHandler::new().with_unknown_sender("someone"));
+        sender.attach_raw_above = Some(0);
+        let body = b"Subject: hi\r\nBcc: hidden@example.com,\r\n other@example.com\r\nTo: alerts@example.com\r\n\r\nbody\r\n".to_vec();
+        let (payloads, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        let attachment = attachment.expect("mail is attached");
+        assert_eq!(attachment.file_name, "message.eml");
+        assert_eq!(
+            attachment.data,
+            b"Subject: hi\r\nTo: alerts@example.com\r\n\r\nbody\r\n".to_vec()
+        );
+        let embeds = payloads[0].0.get("embeds").expect("summary embed");
+        assert_eq!(embeds[0]["fields"][0]["value"], json!("someone"));
+    }
+
+    #[test]
+    fn zipped_mail_is_named_after_the_subject() {
+        let mut sender = test_sender(NoopHandler);
+        sender.attach_raw_above = Some(0);
+        sender.zip_attachments = true;
+        let body = b"Subject: Disk full: /var\r\n\r\nbody\r\n".to_vec();
+        let (_, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        let attachment = attachment.expect("mail is attached");
+        assert_eq!(attachment.file_name, "Disk_full___var.zip");
+        assert_eq!(attachment.mime_type, "application/zip");
+        // Without a subject the envelope id names the zip
+        let (_, attachment) = sender.payloads(&test_envelope(None), b"\r\nbody\r\n", None);
+        assert_eq!(attachment.expect("mail is attached").file_name, "id.zip");
+    }
+
+    #[test]
+    fn zipping_fits_large_mail_under_the_upload_limit() {
+        let mut sender = test_sender(NoopHandler);
+        sender.attach_raw_above = Some(0);
+        let body = format!(
+            "Subject: logs\r\n\r\n{}",
+            "line\r\n".repeat(discord::MAX_UPLOAD_BYTES / 6 + 1)
+        )
+        .into_bytes();
+        let (_, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        assert!(attachment.is_none());
+        sender.zip_attachments = true;
+        let (_, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        assert_eq!(attachment.expect("zip is attached").file_name, "logs.zip");
+    }
+
+    #[test]
+    fn zip_over_the_upload_limit_is_left_out_with_a_note() {
+        let mut sender = test_sender(EmbedHandler::new());
+        sender.attach_raw_above = Some(0);
+        sender.zip_attachments = true;
+        // Bytes from a linear congruential generator, which deflate can't shrink
+        let mut state = 1u32;
+        let noise = (0..discord::MAX_UPLOAD_BYTES + 1024).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        });
+        let body = b"Subject: noise\r\n\r\n"
+            .iter()
+            .copied()
+            .chain(noise)
+            .collect::<Vec<_>>();
+        let (payloads, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        assert!(attachment.is_none());
+        let embeds = payloads[0].0.get("embeds").expect("embed");
+        let fields = embeds[0]["fields"].as_array().expect("fields");
+        assert!(fields.iter().any(|field| field["name"] == "Attachment"));
+    }
+
+    /// Handler that fills an embed with fields each under Discord's field limit
+    #[derive(Clone)]
+    struct LongFieldsHandler;
+
+    impl MailToDiscord for LongFieldsHandler {
+        fn handle(&mut self, _: &Envelope, _: &[u8], webhook_builder: &mut ExecuteWebhook) {
+            let fields = (0..10)
+                .map(|i| json!({"name": format!("Body {}", i), "value": "x".repeat(1000)}))
+                .collect::<Vec<_>>();
+            webhook_builder.embeds(vec![json!({"title": "Logs", "fields": fields})]);
+        }
+    }
+
+    #[test]
+    fn payload_over_total_limit_is_uploaded() {
+        let mut sender = test_sender(LongFieldsHandler);
+        let body = b"Subject: logs\r\n\r\nlots of logs\r\n".to_vec();
+        let (payloads, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        assert_eq!(payloads.len(), 1);
+        assert!(payload_chars(&payloads[0]) <= DEFAULT_MAX_EMBED_CHARS);
+        let embeds = payloads[0].0.get("embeds").expect("summary embed");
+        assert_eq!(embeds[0]["title"], json!("logs"));
+        assert_eq!(attachment.expect("mail is attached").data, body);
+    }
+
+    #[test]
+    fn payload_under_configured_limit_is_posted() {
+        let mut sender = test_sender(LongFieldsHandler);
+        sender.max_embed_chars = 20_000;
+        let (payloads, attachment) = sender.payloads(&test_envelope(None), &[], None);
+        assert!(attachment.is_none());
+        let embeds = payloads[0].0.get("embeds").expect("embed");
+        assert_eq!(embeds[0]["fields"].as_array().map(Vec::len), Some(10));
+    }
+
+    #[test]
+    fn body_preview_attaches_the_whole_body() {
+        let mut sender = test_sender(EmbedHandler::new().with_body_preview_lines(2));
+        let body = b"Subject: logs\r\n\r\none\ntwo\nthree\nfour\n".to_vec();
+        let (payloads, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        let attachment = attachment.expect("body is attached");
+        assert_eq!(attachment.file_name, "body.txt");
+        assert_eq!(attachment.data, b"one\ntwo\nthree\nfour\n".to_vec());
+        let embeds = payloads[0].0.get("embeds").expect("embed");
+        let fields = embeds[0]["fields"].as_array().expect("fields");
+        assert_eq!(
+            fields.last().map(|field| &field["value"]),
+            Some(&json!("one\ntwo\n… 2 more lines not shown"))
+        );
+    }
+
+    #[test]
+    fn split_message_uploads_the_body_once() {
+        let handler = EmbedHandler::new()
+            .with_long_bodies_split(true)
+            .with_body_preview_lines(200);
+        let mut sender = test_sender(handler);
+        sender.split_messages = true;
+        let lines = (0..400)
+            .map(|i| format!("{:03} {}", i, "x".repeat(60)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let body = format!("Subject: logs\r\n\r\n{}\n", lines).into_bytes();
+        let delivery = sender.prepare(test_envelope(None), body, None);
+        assert!(delivery.posts[0].payloads.len() > 1);
+        assert_eq!(delivery.posts[0].targets.len(), 1);
+        let uploads = delivery.posts[0]
+            .parts()
+            .map(|(_, attachment)| attachment.map(|attachment| attachment.file_name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(uploads[0], Some("body.txt"));
+        assert!(uploads[1..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn split_message_is_capped() {
+        let mut sender = test_sender(EmbedHandler::new().with_long_bodies_split(true));
+        sender.split_messages = true;
+        sender.max_split_messages = 2;
+        let body = format!("Subject: logs\r\n\r\n{}\n", "word ".repeat(10_000)).into_bytes();
+        let (payloads, _) = sender.payloads(&test_envelope(None), &body, None);
+        assert_eq!(payloads.len(), 2);
+        let note = payloads[1].0.get("content").and_then(Value::as_str);
+        assert!(note.is_some_and(
+            |note| note.ends_with("more messages left out to keep the channel readable")
+        ));
+    }
+
+    #[test]
+    fn short_body_is_not_attached() {
+        let mut sender = test_sender(EmbedHandler::new().with_body_preview_lines(4));
+        let body = b"Subject: logs\r\n\r\none\ntwo\nthree\nfour\n".to_vec();
+        let (_, attachment) = sender.payloads(&test_envelope(None), &body, None);
+        assert!(attachment.is_none());
+    }
+
+    #[test]
+    fn request_headers_identify_the_bridge() {
+        let headers = request_headers(&HeaderMap::new());
+        assert_eq!(headers[USER_AGENT], DEFAULT_USER_AGENT);
+        let mut configured = HeaderMap::new();
+        configured.insert(USER_AGENT, HeaderValue::from_static("monitor/1.0"));
+        let headers = request_headers(&configured);
+        assert_eq!(headers[USER_AGENT], "monitor/1.0");
+    }
+
+    #[test]
+    fn dedup_key_normalizes_the_sender() {
+        let normalization = SenderNormalization {
+            strip_plus_tags: true,
+            ..SenderNormalization::default()
+        };
+        let tagged = SmtpMail::Mail(test_path("list+bounce-1", "Example.com"));
+        let plain = SmtpMail::Mail(test_path("list", "example.com"));
+        assert_eq!(
+            dedup_key(&normalization, Some(&tagged), "<1@example.com>"),
+            dedup_key(&normalization, Some(&plain), " <1@example.com>")
+        );
+        let other = SmtpMail::Mail(test_path("other", "example.com"));
+        assert_ne!(
+            dedup_key(&normalization, Some(&other), "<1@example.com>"),
+            dedup_key(&normalization, Some(&plain), "<1@example.com>")
+        );
+    }
+
+    /// Builds a mail sink for one recipient
+    ///
+    /// # Parameters
+    /// * `line_endings` - how line endings in the message data are treated
+    fn test_sink(line_endings: LineEndings) -> DiscordMailSink<NoopHandler> {
+        let sender = Arc::new(Mutex::new(test_sender(NoopHandler)));
+        DiscordMailSink::new(test_envelope(None), sender, line_endings)
+    }
+
+    /// Builds a mail sink for a message with a Message-ID, with dedup on and no posts allowed
+    ///
+    /// Returns the sink and the message's dedup key.
+    fn dedup_sink() -> (DiscordMailSink<NoopHandler>, String) {
+        let mut sink = test_sink(LineEndings::Lenient);
+        {
+            let mut sender = sink.sink.lock().expect("sender lock");
+            sender.message_ids = Some(MessageIdCache::new(Duration::from_secs(60)));
+            // Nothing is posted, so the message never reaches Discord
+            sender.post_limit = Some(PostLimiter::new(0, Duration::from_secs(60)));
+        }
+        sink.body = b"Message-ID: <1@example.com>\r\n\r\nbody\r\n".to_vec();
+        let key = dedup_key(&SenderNormalization::default(), None, "<1@example.com>");
+        (sink, key)
+    }
+
+    #[test]
+    fn repeat_of_a_message_being_posted_is_dropped() {
+        let (sink, key) = dedup_sink();
+        let sender = sink.sink.clone();
+        // A first delivery of the message is still being posted
+        let reserve = |key: &str| {
+            let mut sender = sender.lock().expect("sender lock");
+            sender.message_ids.as_mut().expect("dedup on").reserve(key)
+        };
+        assert!(reserve(&key));
+        assert!(matches!(sink.queue(), QueueResult::QueuedWithId(_)));
+        // The repeat was dropped as a duplicate, leaving the first delivery's reservation alone
+        assert!(!reserve(&key));
+    }
+
+    #[test]
+    fn message_id_of_a_dropped_message_is_released() {
+        let (sink, key) = dedup_sink();
+        let sender = sink.sink.clone();
+        assert!(matches!(sink.queue(), QueueResult::QueuedWithId(_)));
+        // The message was dropped over the post limit, so a retry isn't taken for a duplicate
+        let mut sender = sender.lock().expect("sender lock");
+        assert!(sender.message_ids.as_mut().expect("dedup on").reserve(&key));
+    }
+
+    #[test]
+    fn lenient_line_endings_convert_bare_lf() {
+        let mut sink = test_sink(LineEndings::Lenient);
+        // A CRLF split across chunks is left alone
+        for chunk in &["Subject: a\r", "\nb\n", "c\r\n"] {
+            sink.start_send(Bytes::from(*chunk)).expect("accepted");
+        }
+        assert_eq!(sink.body, b"Subject: a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn strict_line_endings_reject_bare_lf() {
+        let mut sink = test_sink(LineEndings::Strict);
+        sink.start_send(Bytes::from("Subject: a\r\n"))
+            .expect("accepted");
+        let e = sink
+            .start_send(Bytes::from("b\n"))
+            .expect_err("bare LF rejected");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dot_stuffed_lines_arrive_unstuffed() {
+        let mut codec = SmtpCodec::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(ClientControll::AcceptData(true), &mut buf)
+            .expect("switched to data");
+        buf.extend_from_slice(b"Subject: a\r\n\r\n..hidden\r\n...\r\nshown\r\n.\r\n");
+        let mut sink = test_sink(LineEndings::Lenient);
+        loop {
+            match codec.decode_either(&mut buf).expect("decoded") {
+                Some(ServerControll::DataChunk(chunk)) => {
+                    sink.start_send(chunk).expect("accepted");
+                }
+                // The session drops escaping dots, as they aren't part of the data
+                Some(ServerControll::EscapeDot(_)) => {}
+                Some(ServerControll::FinalDot(_)) => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(
+            sink.body,
+            b"Subject: a\r\n\r\n.hidden\r\n..\r\nshown".to_vec()
+        );
+    }
+
+    #[test]
+    fn allowed_senders_match_addresses_and_domains() {
+        let mut mailer = DiscordMailer::from_sender("test", test_sender(NoopHandler));
+        mailer.allowed_senders = Some(Arc::new(AllowedSenders::new(vec![
+            "@example.com".into(),
+            "cron@example.org".into(),
+        ])));
+        let from = |local, domain| {
+            let mut request = test_request(test_path("alerts", "example.com"));
+            request.mail = Some(SmtpMail::Mail(test_path(local, domain)));
+            mailer.accept(request).wait()
+        };
+        assert!(matches!(
+            from("backup", "Example.com"),
+            Ok(AcceptRecipientResult::Accepted(_))
+        ));
+        assert!(matches!(
+            from("cron", "example.org"),
+            Ok(AcceptRecipientResult::Accepted(_))
+        ));
+        assert!(matches!(
+            from("intruder", "example.org"),
+            Ok(AcceptRecipientResult::Rejected)
+        ));
+        assert!(matches!(
+            from("backup", "notexample.com"),
+            Ok(AcceptRecipientResult::Rejected)
+        ));
+    }
+
+    #[test]
+    fn body_past_the_size_limit_fails() {
+        let mut sink = test_sink(LineEndings::Strict);
+        sink.max_body_bytes = 16;
+        sink.start_send(Bytes::from("Subject: a\r\n"))
+            .expect("accepted");
+        let e = sink
+            .start_send(Bytes::from("\r\nbody\r\n"))
+            .expect_err("body too large");
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn converted_line_endings_count_toward_the_size_limit() {
+        let mut sink = test_sink(LineEndings::Lenient);
+        sink.max_body_bytes = 16;
+        // A CRLF split across chunks gets no extra CR, so this is fifteen bytes
+        for chunk in &["Subject: a\r", "\nb\n"] {
+            sink.start_send(Bytes::from(*chunk)).expect("accepted");
+        }
+        // The LF fits as sent, but not with the CR it is given
+        let e = sink
+            .start_send(Bytes::from("\n"))
+            .expect_err("body too large");
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(sink.body.len(), 15);
+    }
+
+    #[test]
+    fn service_name_reaches_the_mailer() {
+        let mailer = DiscordMailerBuilder::new()
+            .with_name("relay.example.com")
+            .build_with_sender(test_sender(NoopHandler))
+            .expect("mailer built");
+        assert_eq!(mailer.name(), "relay.example.com");
+        let mailer = DiscordMailerBuilder::new()
+            .build_with_sender(test_sender(NoopHandler))
+            .expect("mailer built");
+        assert_eq!(mailer.name(), DEFAULT_SERVICE_NAME);
+    }
+
+    #[test]
+    fn mail_without_a_sender_is_handled() {
+        let mailer = DiscordMailer::from_sender("test", test_sender(EmbedHandler::new()));
+        let mut sink = mailer
+            .mail(test_envelope(None))
+            .wait()
+            .expect("mail started")
+            .expect("mail accepted");
+        sink.start_send(Bytes::from("Subject: Hi\r\n\r\nHi\r\n"))
+            .expect("accepted");
+        let envelope = sink.envelope.clone();
+        let body = sink.body.clone();
+        let mut sender = sink.sink.lock().unwrap();
+        let (payloads, _) = sender.payloads(&envelope, &body, None);
+        assert_eq!(payloads.len(), 1);
+    }
+
+    /// Builds a failed request's error with a status code
+    ///
+    /// # Parameters
+    /// * `status_code` - the response's status code
+    fn unsuccessful(status_code: u16) -> serenity::Error {
+        HttpError::UnsuccessfulRequest(ErrorResponse {
+            status_code: StatusCode::from_u16(status_code).expect("valid status code"),
+            url: "https://discord.com/api/webhooks/1/token".parse().unwrap(),
+            error: serde_json::from_value(json!({"code": 0, "message": "failed"})).unwrap(),
+        })
+        .into()
+    }
+
+    #[test]
+    fn server_errors_and_rate_limits_are_transient() {
+        assert!(is_transient(&unsuccessful(500)));
+        assert!(is_transient(&unsuccessful(503)));
+        assert!(is_transient(&unsuccessful(429)));
+        assert!(!is_transient(&unsuccessful(400)));
+        assert!(!is_transient(&unsuccessful(404)));
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_the_limits() {
+        let retry = RetryPolicy::new(3, Duration::from_millis(100), Duration::from_secs(1));
+        let delay = |attempt| retry.delay(attempt, Duration::from_millis(0));
+        assert_eq!(delay(0), Some(Duration::from_millis(100)));
+        assert_eq!(delay(2), Some(Duration::from_millis(400)));
+        assert_eq!(delay(3), None);
+        // A retry that would end past the total time limit isn't made
+        assert_eq!(retry.delay(1, Duration::from_millis(900)), None);
+        // Huge attempt counts give up rather than overflowing
+        let retry = RetryPolicy::new(u32::MAX, Duration::from_secs(1), Duration::MAX);
+        assert_eq!(retry.delay(40, Duration::from_secs(0)), None);
+        assert_eq!(retry.delay(u32::MAX - 1, Duration::from_secs(0)), None);
+    }
+
+    #[test]
+    fn continuation_starts_a_new_message_when_its_field_would_not_fit() {
+        let field = json!({"name": "Body", "value": "x".repeat(1000), "inline": false});
+        let mut payload = ExecuteWebhook::default();
+        payload.embeds(vec![json!({
+            "title": "T",
+            "fields": vec![field; 5],
+        })]);
+        let max_chars = 2100;
+        let messages = split_payload(payload, max_chars);
+        assert_eq!(messages.len(), 3);
+        for message in &messages {
+            let chars: usize = message.0["embeds"]
+                .as_array()
+                .expect("embeds")
+                .iter()
+                .map(embed_chars)
+                .sum();
+            assert!(chars <= max_chars, "{} chars", chars);
+        }
+        assert_eq!(messages[1].0["embeds"][0]["title"], "T (cont.)");
     }
 }
@@ -15,40 +15,20 @@
 // along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
 
 use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
-use samotop::model::command::SmtpMail;
-use samotop::model::mail::Envelope;
-use samotop::service::session::StatefulSessionService;
-use samotop::service::tcp::SamotopService;
-use serenity::builder::ExecuteWebhook;
-use serenity::model::channel::Embed;
+use futures::future::{self, Future};
+use log::warn;
+use samotop::model::controll::TlsConfig;
 use smtp_discord_bridge::config::Config;
-use smtp_discord_bridge::smtp::wrap_mailer_service;
-use smtp_discord_bridge::{DiscordMailerBuilder, MailToDiscord};
-use std::fs;
-use std::net::SocketAddr;
-
-#[derive(Clone)]
-struct MMTD;
-
-impl MailToDiscord for MMTD {
-    fn handle(&mut self, envelope: Envelope, body: Vec<u8>, webhook_builder: &mut ExecuteWebhook) {
-        use SmtpMail::*;
-        let sender = match envelope.mail.unwrap() {
-            Mail(p) => p,
-            Send(p) => p,
-            Saml(p) => p,
-            Soml(p) => p,
-        };
-        let rcpt = envelope.rcpts.get(0).unwrap();
-        let embed = Embed::fake(|e| {
-            e.title("New Message")
-                .field("From", sender.to_string(), true)
-                .field("To", rcpt.to_string(), true)
-                .field("Body", String::from_utf8(body).unwrap(), false)
-        });
-        webhook_builder.embeds(vec![embed]);
-    }
-}
+use smtp_discord_bridge::discord::DiscordWebhookAuth;
+use smtp_discord_bridge::handler::ExternalFilterHandler;
+use smtp_discord_bridge::smtp::{
+    serve_listener, wrap_mailer_session, DataIdleWatch, KeepAliveService,
+};
+use smtp_discord_bridge::systemd;
+use smtp_discord_bridge::{DiscordMailerBuilder, MailToDiscord, RetryPolicy};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::time::Duration;
 
 /// Configuration path
 const ARG_CONFIG_PATH: &str = "config_path";
@@ -78,13 +58,21 @@ fn main() {
         .value_of(ARG_CONFIG_PATH)
         .expect("Missing config file");
 
-    // Read the config file as bytes
-    let config_contents = fs::read(config_path).expect("Failed to read config file");
-    // Parse the config file
-    let config: Config = toml::from_slice(&config_contents).expect("Failed to parse config file");
+    // Read and parse the config file
+    let config = Config::from_file(Path::new(config_path)).expect("Failed to load config file");
 
-    // Get the listen address
-    let listen_addr: SocketAddr = (&config.smtp).into();
+    // Get the TLS settings
+    let tls_config = config.smtp.tls_config();
+    // Get the listen addresses
+    let listen_addrs = config.smtp.socket_addrs();
+    // Get the keep-alive interval for accepted connections
+    let keepalive = config.smtp.keepalive();
+    // Get the time the message data rate is averaged over
+    let data_rate_window = config.smtp.data_rate_window();
+    // Get how long message data may stall before the connection is closed
+    let data_idle_timeout = config.smtp.data_idle_timeout();
+    // Get how long posted Message-IDs are remembered
+    let message_id_dedup_ttl = config.smtp.message_id_dedup_ttl();
 
     // Get the Discord webhook id and token
     let discord_webhook_auth = config
@@ -92,20 +80,233 @@ fn main() {
         .get_auth()
         .expect("Failed to get Discord auth from config");
 
+    // Get the extra HTTP headers for webhook requests, checked when the config was loaded
+    let http_headers = config
+        .discord
+        .http_headers()
+        .expect("Invalid HTTP headers in config");
+
+    // Get the recipient routes, each with its own handler if it sets its own format
+    let routes = config.routes().expect("Invalid routes in config");
+
     // Build a Discord-based mailer
-    let mailer_builder = DiscordMailerBuilder::new();
+    let mailer_builder = DiscordMailerBuilder::new().with_http_headers(http_headers);
     // Add name if specified in the config
     let mailer_builder = if let Some(name) = config.smtp.service_name {
         mailer_builder.with_name(&name)
     } else {
         mailer_builder
     };
+    // Set how sender addresses are normalized before they are matched
+    let mailer_builder =
+        mailer_builder.with_sender_normalization(config.smtp.sender_normalization.clone());
+    // Set how line endings in the message data are treated
+    let mailer_builder = mailer_builder.with_line_endings(config.smtp.line_endings);
+    // Drop repeated Message-IDs if enabled in the config
+    let mailer_builder = if let Some(ttl) = message_id_dedup_ttl {
+        mailer_builder.with_message_id_dedup(ttl)
+    } else {
+        mailer_builder
+    };
+    // Refuse mail missing any required headers
+    let mailer_builder = mailer_builder.with_required_headers(config.smtp.required_headers);
+    // Show transaction timing if enabled in the config
+    let mailer_builder =
+        mailer_builder.with_transaction_timing(config.smtp.show_transaction_timing);
+    // Abort transactions whose data trickles in below the configured rate
+    let mailer_builder = if config.smtp.min_data_rate > 0 {
+        mailer_builder.with_min_data_rate(config.smtp.min_data_rate, data_rate_window)
+    } else {
+        mailer_builder
+    };
+    // Restrict recipients to the served domains, warning if the bridge would relay for anyone
+    if config.smtp.served_domains.is_empty() {
+        warn!("No served_domains configured, accepting recipients in every domain");
+    }
+    let mailer_builder = mailer_builder.with_served_domains(config.smtp.served_domains);
+    // Abort transactions whose body grows past the configured size
+    let mailer_builder = mailer_builder.with_max_body_bytes(config.smtp.max_body_bytes);
+    // Only relay for allowed senders if specified in the config
+    let mailer_builder = if let Some(allowed_senders) = config.smtp.allowed_senders {
+        mailer_builder.with_allowed_senders(allowed_senders)
+    } else {
+        mailer_builder
+    };
+    // Limit messages per connection if specified in the config
+    let mailer_builder = if let Some(max_messages) = config.smtp.max_messages_per_connection {
+        mailer_builder.with_max_messages_per_connection(max_messages)
+    } else {
+        mailer_builder
+    };
+    // Let mail to command recipients control the bridge
+    let mailer_builder = mailer_builder.with_commands(config.smtp.command_recipients);
+    // Pause on a control file if specified in the config
+    let mailer_builder = if let Some(pause_file) = config.smtp.pause_file {
+        mailer_builder.with_pause_file(pause_file)
+    } else {
+        mailer_builder
+    };
+    // Set how often repeated send failures are logged
+    let mailer_builder = mailer_builder.with_error_log_throttle(
+        config.discord.error_log_every,
+        config.discord.error_log_interval(),
+    );
+    // Fetch the webhook again in the background if enabled in the config
+    let mailer_builder = if let Some(refresh_interval) = config.discord.refresh_interval() {
+        mailer_builder.with_refresh_interval(refresh_interval)
+    } else {
+        mailer_builder
+    };
+    // Post mail for routed recipients to their own webhooks
+    let mailer_builder = mailer_builder
+        .with_routes(routes)
+        .with_routing_default(config.discord.routing_default);
+    // Upload large mail as a file if specified in the config
+    let mailer_builder = if config.discord.attach_raw {
+        mailer_builder.with_raw_attachment(config.discord.attach_raw_above)
+    } else {
+        mailer_builder
+    };
+    // Zip uploaded files if enabled in the config
+    let mailer_builder = mailer_builder.with_zipped_attachments(config.discord.zip_attachments);
+    // Set how transient send failures are retried
+    let mailer_builder = mailer_builder.with_retries(RetryPolicy::new(
+        config.discord.retry_attempts,
+        config.discord.retry_base(),
+        config.discord.retry_max_total(),
+    ));
+    // Cap how many messages are posted per window if specified in the config
+    let mailer_builder = if let Some(max_posts) = config.discord.max_posts {
+        mailer_builder.with_post_limit(max_posts, config.discord.max_posts_window())
+    } else {
+        mailer_builder
+    };
+    // Post each mail conversation in its own thread if enabled in the config
+    let mailer_builder = if let Some(ttl) = config.discord.conversation_threads_ttl() {
+        mailer_builder.with_conversation_threads(ttl)
+    } else {
+        mailer_builder
+    };
+    // Set how long the embeds may be before fields are dropped
+    let mailer_builder = mailer_builder.with_max_embed_chars(config.discord.max_embed_chars);
+    // Post long bodies across several messages if the format asks for it
+    let mailer_builder = mailer_builder.with_message_splitting(config.format.split_long_bodies());
+    let mailer_builder = mailer_builder.with_max_split_messages(config.format.max_split_messages());
+    // Post a notice on startup if enabled in the config
+    let mailer_builder = mailer_builder.with_startup_notice(config.discord.startup_notice);
+    // Check the webhook in the background if enabled in the config
+    let mailer_builder = if let Some(ping_interval) = config.discord.ping_interval() {
+        mailer_builder.with_ping_interval(ping_interval)
+    } else {
+        mailer_builder
+    };
+    // Add empty message placeholder if specified in the config
+    let mailer_builder = if let Some(empty_message) = config.discord.empty_message {
+        mailer_builder.with_empty_message(&empty_message)
+    } else {
+        mailer_builder
+    };
+    // Honor upstream spam headers if configured
+    let mailer_builder = if let Some(spam_check) = config.spam {
+        mailer_builder.with_spam_check(spam_check)
+    } else {
+        mailer_builder
+    };
+    // Build the default handler from the format settings
+    let embed_handler = config
+        .format
+        .embed_handler(&config.smtp.sender_normalization)
+        .expect("Invalid format settings in config");
+    // Pick the mail handler, piping through an external filter if one is configured
+    match config.filter {
+        Some(filter) => {
+            let timeout = filter.timeout();
+            let handler =
+                ExternalFilterHandler::new(&filter.command, filter.args, timeout, embed_handler);
+            run(
+                mailer_builder,
+                &discord_webhook_auth,
+                listen_addrs,
+                keepalive,
+                data_idle_timeout,
+                tls_config,
+                handler,
+            )
+        }
+        None => run(
+            mailer_builder,
+            &discord_webhook_auth,
+            listen_addrs,
+            keepalive,
+            data_idle_timeout,
+            tls_config,
+            embed_handler,
+        ),
+    }
+}
+
+/// Builds the mailer around a handler and runs the SMTP service
+///
+/// # Parameters
+/// * `mailer_builder` - configured Discord mailer builder
+/// * `webhook_auth` - Discord webhook id and auth info
+/// * `listen_addrs` - addresses the SMTP server listens on
+/// * `keepalive` - TCP keep-alive interval for accepted connections
+/// * `data_idle_timeout` - how long message data may stall before the connection is closed
+/// * `tls_config` - whether and how TLS is offered to clients
+/// * `handler` - Object used to generate messages from email
+fn run<T>(
+    mailer_builder: DiscordMailerBuilder,
+    webhook_auth: &DiscordWebhookAuth,
+    listen_addrs: Vec<SocketAddr>,
+    keepalive: Option<Duration>,
+    data_idle_timeout: Option<Duration>,
+    tls_config: TlsConfig,
+    handler: T,
+) where
+    T: Clone + MailToDiscord + Send + 'static,
+{
+    // Watch for stalled message data if enabled in the config
+    let data_idle_watch = DataIdleWatch::new();
+    let mailer_builder = if data_idle_timeout.is_some() {
+        mailer_builder.with_data_idle_watch(data_idle_watch.clone())
+    } else {
+        mailer_builder
+    };
     // Build mailer
     let mailer = mailer_builder
-        .build(&discord_webhook_auth, MMTD)
+        .build(webhook_auth, handler)
         .expect("Failed to create Discord mailer");
-    // Wrap the mailer service
-    let smtp_service = wrap_mailer_service(mailer).on(listen_addr);
-    // Run the service
-    tokio::run(smtp_service.build_task());
+    // Wrap the mailer in the SMTP services, setting keep-alive on each connection
+    let smtp_service = KeepAliveService::new(wrap_mailer_session(mailer, tls_config), keepalive);
+    // Close connections whose message data stalls
+    let smtp_service = if let Some(timeout) = data_idle_timeout {
+        smtp_service.with_data_idle_timeout(data_idle_watch, timeout)
+    } else {
+        smtp_service
+    };
+    // Use sockets passed in by systemd if socket activated, otherwise bind the configured addresses
+    let mut listeners = systemd::listeners();
+    if listeners.is_empty() {
+        listeners = listen_addrs
+            .into_iter()
+            .map(|listen_addr| {
+                TcpListener::bind(listen_addr)
+                    .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", listen_addr, e))
+            })
+            .collect();
+    }
+    let smtp_task = future::join_all(
+        listeners
+            .into_iter()
+            .map(move |listener| serve_listener(listener, smtp_service.clone())),
+    )
+    .map(|_| ());
+    // Run the service, telling systemd we're up now every listener is bound
+    tokio::run(future::lazy(|| {
+        if let Err(e) = systemd::notify_ready() {
+            warn!("Failed to notify systemd: {}", e);
+        }
+        smtp_task
+    }));
 }
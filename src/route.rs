@@ -0,0 +1,282 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::discord::DiscordWebhookAuth;
+use crate::handler::EmbedHandler;
+use crate::smtp::ServedDomains;
+use samotop::model::command::{SmtpAddress, SmtpHost, SmtpPath};
+use serde::Deserialize;
+
+/// What happens to mail for recipients no route matches
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingDefault {
+    /// The mail is posted to the default webhook
+    #[default]
+    Fallback,
+    /// The mail is accepted but not posted for those recipients
+    Drop,
+    /// The recipients are rejected
+    Reject,
+}
+
+/// Where a route's mail is posted, and how it looks there
+#[derive(Clone)]
+pub struct RouteDestination {
+    /// Discord webhook id and auth info
+    pub auth: DiscordWebhookAuth,
+    /// Embed color used in place of the handler's, if any
+    pub color: Option<u32>,
+    /// Handler used in place of the default one, if the route has its own format
+    pub handler: Option<EmbedHandler>,
+}
+
+impl RouteDestination {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `auth` - Discord webhook id and auth info
+    pub fn new(auth: DiscordWebhookAuth) -> Self {
+        Self {
+            auth,
+            color: None,
+            handler: None,
+        }
+    }
+
+    /// Sets the embed color used in place of the handler's
+    ///
+    /// # Parameters
+    /// * `color` - the embed color as 0xRRGGBB
+    pub fn with_color(mut self, color: u32) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    /// Sets the handler used in place of the default one
+    ///
+    /// # Parameters
+    /// * `handler` - Object that converts mail to Discord webhook messages for this route
+    pub fn with_handler(mut self, handler: EmbedHandler) -> Self {
+        self.handler = Some(handler);
+        self
+    }
+}
+
+/// Recipient patterns mapped to destinations
+///
+/// A pattern with a local part, such as `alerts@example.com`, matches that address exactly. A
+/// local part with `*` wildcards, such as `alert-*@example.com`, matches every local part it
+/// fits, and a domain of `*` matches any domain. A pattern without a local part, such as
+/// `@example.com` or `*.example.com`, matches every address in the domain, with `*.` matching
+/// subdomains as in the served domains. Exact addresses win over local part patterns, which win
+/// over domains, and otherwise the first matching route is used.
+#[derive(Clone, Debug)]
+pub struct Routes<D> {
+    /// Patterns and their destinations, in order
+    routes: Vec<(String, D)>,
+}
+
+impl<D> Routes<D> {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `routes` - patterns and their destinations, in order
+    pub fn new(routes: Vec<(String, D)>) -> Self {
+        Self { routes }
+    }
+
+    /// Returns whether there are no routes
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Converts every destination, stopping at the first failure
+    ///
+    /// # Parameters
+    /// * `convert` - converts a destination
+    pub fn try_map<T, E, F>(self, mut convert: F) -> Result<Routes<T>, E>
+    where
+        F: FnMut(D) -> Result<T, E>,
+    {
+        let routes = self
+            .routes
+            .into_iter()
+            .map(|(pattern, destination)| Ok((pattern, convert(destination)?)))
+            .collect::<Result<_, E>>()?;
+        Ok(Routes::new(routes))
+    }
+
+    /// Finds the destination for a recipient, if any route matches it
+    ///
+    /// # Parameters
+    /// * `rcpt` - the recipient path
+    pub fn resolve(&self, rcpt: &SmtpPath) -> Option<&D> {
+        let (local, domain) = match rcpt {
+            SmtpPath::Direct(SmtpAddress::Mailbox(local, SmtpHost::Domain(domain)))
+            | SmtpPath::Relay(_, SmtpAddress::Mailbox(local, SmtpHost::Domain(domain))) => {
+                (local, domain)
+            }
+            // The postmaster, bounces and address literals go to the default destination
+            _ => return None,
+        };
+        let address = format!("{}@{}", local, domain.trim_end_matches('.'));
+        let exact = self.routes.iter().find(|(pattern, _)| {
+            has_local_part(pattern)
+                && !pattern.contains('*')
+                && pattern.eq_ignore_ascii_case(&address)
+        });
+        let local_part_match = || {
+            self.routes.iter().find(|(pattern, _)| {
+                let (local_pattern, domain_pattern) = match pattern.rsplit_once('@') {
+                    Some(parts) if has_local_part(pattern) && pattern.contains('*') => parts,
+                    _ => return false,
+                };
+                let domain_matches = domain_pattern == "*"
+                    || ServedDomains::new(vec![domain_pattern.into()]).serves(domain);
+                domain_matches && glob_matches(local_pattern, local)
+            })
+        };
+        let domain_match = || {
+            self.routes.iter().find(|(pattern, _)| {
+                !has_local_part(pattern)
+                    && ServedDomains::new(vec![pattern.trim_start_matches('@').into()])
+                        .serves(domain)
+            })
+        };
+        exact
+            .or_else(local_part_match)
+            .or_else(domain_match)
+            .map(|(_, destination)| destination)
+    }
+
+    /// Checks whether any route matches a recipient
+    ///
+    /// # Parameters
+    /// * `rcpt` - the recipient path
+    pub fn matches(&self, rcpt: &SmtpPath) -> bool {
+        self.resolve(rcpt).is_some()
+    }
+}
+
+/// Checks whether a route pattern names a single address rather than a domain
+///
+/// # Parameters
+/// * `pattern` - the route pattern
+fn has_local_part(pattern: &str) -> bool {
+    pattern.rfind('@').is_some_and(|at| at > 0)
+}
+
+/// Checks whether a local part fits a pattern, ignoring case
+///
+/// A `*` in the pattern matches any run of characters, including none.
+///
+/// # Parameters
+/// * `pattern` - the local part pattern
+/// * `local` - the local part to check
+fn glob_matches(pattern: &str, local: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let local = local.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    // The text before the first wildcard is anchored at the start
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match local.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    // And the text after the last wildcard is anchored at the end
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(start) => rest = &rest[start + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl<D> Default for Routes<D> {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a mailbox path
+    ///
+    /// # Parameters
+    /// * `local` - the local part
+    /// * `domain` - the domain
+    fn path(local: &str, domain: &str) -> SmtpPath {
+        SmtpPath::Direct(SmtpAddress::Mailbox(
+            local.into(),
+            SmtpHost::Domain(domain.into()),
+        ))
+    }
+
+    /// Builds routes from patterns and destination names
+    ///
+    /// # Parameters
+    /// * `routes` - patterns and their destinations, in order
+    fn routes(routes: &[(&str, &'static str)]) -> Routes<&'static str> {
+        Routes::new(
+            routes
+                .iter()
+                .map(|&(pattern, destination)| (pattern.into(), destination))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn local_part_patterns_route_catch_all_mail() {
+        let routes = routes(&[
+            ("alert-*@example.com", "alerts"),
+            ("*-report@example.com", "reports"),
+            ("backup*job*@*", "backups"),
+        ]);
+        let resolve = |local, domain| routes.resolve(&path(local, domain)).copied();
+        assert_eq!(resolve("alert-disk", "example.com"), Some("alerts"));
+        assert_eq!(resolve("ALERT-", "example.com"), Some("alerts"));
+        assert_eq!(resolve("weekly-report", "example.com"), Some("reports"));
+        assert_eq!(
+            resolve("backup-nightly-job", "other.example"),
+            Some("backups")
+        );
+        assert_eq!(resolve("alert-disk", "other.example"), None);
+        assert_eq!(resolve("alerts", "example.com"), None);
+        assert_eq!(resolve("report-weekly", "example.com"), None);
+    }
+
+    #[test]
+    fn exact_addresses_beat_local_part_patterns_which_beat_domains() {
+        let routes = routes(&[
+            ("@example.com", "domain"),
+            ("alert-*@example.com", "pattern"),
+            ("alert-disk@example.com", "exact"),
+        ]);
+        let resolve = |local| routes.resolve(&path(local, "example.com")).copied();
+        assert_eq!(resolve("alert-disk"), Some("exact"));
+        assert_eq!(resolve("alert-cpu"), Some("pattern"));
+        assert_eq!(resolve("root"), Some("domain"));
+    }
+}
@@ -1,7 +1,283 @@
+use crate::discord::SenderNormalization;
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use log::{error, info, warn};
+use samotop::model::command::{SmtpAddress, SmtpHost, SmtpMail, SmtpPath};
 use samotop::model::controll::{TlsConfig, TlsIdFile, TlsMode};
 use samotop::server::SamotopBuilder;
 use samotop::service::session::StatefulSessionService;
 use samotop::service::tcp::SamotopService;
+use samotop::service::TcpService;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::timer::Interval;
+
+/// Longest time between checks for connections whose message data has stalled
+pub const DATA_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How line endings in the message data are treated
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineEndings {
+    /// Bare LF line endings are accepted and converted to CRLF
+    #[default]
+    Lenient,
+    /// Bare LF line endings abort the transaction
+    Strict,
+}
+
+/// Whether and how TLS is offered to SMTP clients
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsSetting {
+    /// TLS isn't offered
+    #[default]
+    Disabled,
+    /// Clients may upgrade to TLS with STARTTLS
+    Starttls,
+    /// Clients must upgrade to TLS with STARTTLS before sending mail
+    StarttlsRequired,
+    /// Connections are TLS from the start
+    Enabled,
+}
+
+impl From<TlsSetting> for TlsMode {
+    fn from(setting: TlsSetting) -> Self {
+        match setting {
+            TlsSetting::Disabled => TlsMode::Disabled,
+            TlsSetting::Starttls => TlsMode::StartTlsOptional,
+            TlsSetting::StarttlsRequired => TlsMode::StartTlsRquired,
+            TlsSetting::Enabled => TlsMode::Enabled,
+        }
+    }
+}
+
+/// Domains the server accepts recipients for
+///
+/// An empty list accepts every domain. A pattern starting with `*.` matches any subdomain of
+/// the rest of the pattern, but not the domain itself.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ServedDomains {
+    /// Domain patterns, matched case insensitively
+    patterns: Vec<String>,
+}
+
+impl ServedDomains {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `patterns` - domains or `*.` wildcard patterns to serve
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self { patterns }
+    }
+
+    /// Returns whether every domain is accepted
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Checks whether a domain is served
+    ///
+    /// # Parameters
+    /// * `domain` - the domain to check
+    pub fn serves(&self, domain: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.patterns.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(parent) => domain
+                    .strip_suffix(parent)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => domain == pattern,
+            }
+        })
+    }
+
+    /// Checks whether a recipient path is addressed to a served domain
+    ///
+    /// The postmaster is always accepted, since every mail server has to take mail for it.
+    ///
+    /// # Parameters
+    /// * `rcpt` - the recipient path
+    pub fn accepts(&self, rcpt: &SmtpPath) -> bool {
+        let host = match rcpt {
+            SmtpPath::Postmaster => return true,
+            SmtpPath::Null => return self.is_empty(),
+            SmtpPath::Direct(SmtpAddress::Mailbox(_, host)) => host,
+            SmtpPath::Relay(_, SmtpAddress::Mailbox(_, host)) => host,
+        };
+        match host {
+            SmtpHost::Domain(domain) => self.serves(domain),
+            // Address literals never match a served domain
+            _ => self.is_empty(),
+        }
+    }
+}
+
+/// Senders allowed to relay mail through the bridge
+///
+/// An entry starting with `@` matches every address in that domain, and any other entry matches
+/// one address exactly. Both are matched case insensitively, after normalizing the sender and
+/// the entry alike.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct AllowedSenders {
+    /// Addresses and `@domain` suffixes
+    entries: Vec<String>,
+}
+
+impl AllowedSenders {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `entries` - addresses and `@domain` suffixes to allow
+    pub fn new(entries: Vec<String>) -> Self {
+        Self { entries }
+    }
+
+    /// Checks whether a sender may relay mail
+    ///
+    /// The null reverse path used for bounces is only allowed by an entry of `<>`.
+    ///
+    /// # Parameters
+    /// * `mail` - the MAIL FROM command, if one was given
+    /// * `normalization` - how the sender and entries are normalized before matching
+    pub fn allows(&self, mail: Option<&SmtpMail>, normalization: &SenderNormalization) -> bool {
+        let path = match mail {
+            Some(mail) => SmtpMail::from(mail),
+            None => return false,
+        };
+        if *path == SmtpPath::Null {
+            return self.entries.iter().any(|entry| entry == "<>");
+        }
+        let address = normalization.normalize(&path.to_string());
+        let domain = address.rfind('@').map(|at| &address[at..]);
+        self.entries.iter().any(|entry| {
+            if entry.starts_with('@') {
+                domain.is_some_and(|domain| entry.eq_ignore_ascii_case(domain))
+            } else {
+                normalization
+                    .normalize(entry)
+                    .eq_ignore_ascii_case(&address)
+            }
+        })
+    }
+}
+
+/// Aborts a transaction whose message data arrives too slowly
+///
+/// The rate is measured over consecutive windows starting with the first data received, so
+/// a sender has to keep up the minimum rate for the whole transaction.
+#[derive(Clone, Copy, Debug)]
+pub struct DataRateMonitor {
+    /// Minimum average rate in bytes per second over a window
+    min_rate: u64,
+    /// Length of each measurement window
+    window: Duration,
+    /// When the current window started, if any data has arrived
+    window_start: Option<Instant>,
+    /// Bytes received in the current window
+    window_bytes: u64,
+}
+
+impl DataRateMonitor {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `min_rate` - minimum average rate in bytes per second
+    /// * `window` - length of each measurement window
+    pub fn new(min_rate: u64, window: Duration) -> Self {
+        Self {
+            min_rate,
+            window,
+            window_start: None,
+            window_bytes: 0,
+        }
+    }
+
+    /// Counts received bytes, failing if the last full window was below the minimum rate
+    ///
+    /// # Parameters
+    /// * `bytes` - the number of bytes just received
+    pub fn record(&mut self, bytes: usize) -> io::Result<()> {
+        let now = Instant::now();
+        let window_start = *self.window_start.get_or_insert(now);
+        self.window_bytes += bytes as u64;
+        let elapsed = now.duration_since(window_start);
+        if elapsed < self.window {
+            return Ok(());
+        }
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        if rate < self.min_rate as f64 {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "message data arriving at {:.1} B/s, below the minimum of {} B/s",
+                    rate, self.min_rate
+                ),
+            ));
+        }
+        self.window_start = Some(now);
+        self.window_bytes = 0;
+        Ok(())
+    }
+}
+
+/// Tracks when each connection in the middle of a transfer last received message data
+///
+/// The data rate is only checked when data arrives, so a sender that stops sending altogether
+/// is caught here instead. Connections are keyed by their peer address.
+#[derive(Clone, Debug, Default)]
+pub struct DataIdleWatch {
+    /// When message data last arrived from each peer mid-transfer
+    last_data: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+}
+
+impl DataIdleWatch {
+    /// Constructor
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records message data arriving from a peer, starting to watch it if it wasn't already
+    ///
+    /// # Parameters
+    /// * `peer` - the peer's address
+    pub fn record(&self, peer: SocketAddr) {
+        if let Ok(mut last_data) = self.last_data.lock() {
+            last_data.insert(peer, Instant::now());
+        }
+    }
+
+    /// Stops watching a peer, once its message data is complete or it has gone
+    ///
+    /// # Parameters
+    /// * `peer` - the peer's address
+    pub fn end(&self, peer: SocketAddr) {
+        if let Ok(mut last_data) = self.last_data.lock() {
+            last_data.remove(&peer);
+        }
+    }
+
+    /// Returns how long it has been since message data arrived from a peer mid-transfer
+    ///
+    /// # Parameters
+    /// * `peer` - the peer's address
+    pub fn idle(&self, peer: SocketAddr) -> Option<Duration> {
+        let last_data = self.last_data.lock().ok()?;
+        last_data.get(&peer).map(Instant::elapsed)
+    }
+}
 
 /// Returns a TlsConfig that doesn't use TLS
 pub fn tls_config_none() -> TlsConfig {
@@ -16,16 +292,210 @@ pub fn tls_config_none() -> TlsConfig {
 
 pub fn wrap_mailer_service<S>(
     mailer_service: S,
+    tls_conf: TlsConfig,
 ) -> SamotopBuilder<SamotopService<StatefulSessionService<S>>> {
+    // Wraps the custom service in a samotop builder
+    samotop::builder().with(wrap_mailer_session(mailer_service, tls_conf))
+}
+
+/// Wraps a mailer service in the SMTP session and TCP services
+///
+/// # Parameters
+/// * `mailer_service` - the mail service handling each SMTP transaction
+/// * `tls_conf` - whether and how TLS is offered to clients
+pub fn wrap_mailer_session<S>(
+    mailer_service: S,
+    tls_conf: TlsConfig,
+) -> SamotopService<StatefulSessionService<S>> {
     // Wrap the mailer service in a stateful SMTP session
     let custom_session_svc = StatefulSessionService::new(mailer_service);
 
-    // TODO: allow the option for TLS
-    let tls_conf = tls_config_none();
-
     // Wrap the stateful SMTP session in a TCP service
-    let custom_svc = SamotopService::new(custom_session_svc, tls_conf);
+    SamotopService::new(custom_session_svc, tls_conf)
+}
 
-    // Wraps the custom service in a samotop builder
-    samotop::builder().with(custom_svc)
+/// TCP service that sets keep-alive on each connection before handing it on
+///
+/// SMTP's NOOP is already answered with a plain 250 that leaves the transaction alone, so this
+/// only covers the socket side of keeping long-lived sending connections open. It can also close
+/// connections whose message data stops arriving.
+#[derive(Clone)]
+pub struct KeepAliveService<S> {
+    /// The service handling each connection
+    inner: S,
+    /// Keep-alive interval, or None to leave the OS default
+    keepalive: Option<Duration>,
+    /// Transfers being watched, and how long their data may stall, if stalls are caught
+    data_idle: Option<(DataIdleWatch, Duration)>,
+}
+
+impl<S> KeepAliveService<S> {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `inner` - the service handling each connection
+    /// * `keepalive` - keep-alive interval, or None to leave the OS default
+    pub fn new(inner: S, keepalive: Option<Duration>) -> Self {
+        Self {
+            inner,
+            keepalive,
+            data_idle: None,
+        }
+    }
+
+    /// Closes connections whose message data stops arriving for too long
+    ///
+    /// # Parameters
+    /// * `watch` - the transfers being watched, shared with the mailer
+    /// * `timeout` - how long message data may stop arriving
+    pub fn with_data_idle_timeout(mut self, watch: DataIdleWatch, timeout: Duration) -> Self {
+        self.data_idle = Some((watch, timeout));
+        self
+    }
+}
+
+impl<S> TcpService for KeepAliveService<S>
+where
+    S: TcpService,
+    S::Future: Send + 'static,
+{
+    type Future = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    fn handle(self, stream: TcpStream) -> Self::Future {
+        if let Some(keepalive) = self.keepalive {
+            if let Err(e) = stream.set_keepalive(Some(keepalive)) {
+                warn!("Failed to set TCP keep-alive: {}", e);
+            }
+        }
+        let peer = stream.peer_addr().ok();
+        let connection = self.inner.handle(stream);
+        let (watch, timeout, peer) = match (self.data_idle, peer) {
+            (Some((watch, timeout)), Some(peer)) => (watch, timeout, peer),
+            _ => return Box::new(connection),
+        };
+        // Check often enough to close a stalled connection close to its timeout
+        let stalled = {
+            let watch = watch.clone();
+            Interval::new_interval(timeout.min(DATA_IDLE_CHECK_INTERVAL))
+                .map_err(|e| error!("Data idle timer failed: {}", e))
+                .take_while(move |_| Ok(watch.idle(peer).is_none_or(|idle| idle < timeout)))
+                .for_each(|_| Ok(()))
+                .map(move |()| {
+                    warn!(
+                        "Closing connection from {} after its message data stalled for {:?}",
+                        peer, timeout
+                    )
+                })
+        };
+        // Whichever finishes first ends the connection, dropping the other
+        Box::new(connection.select(stalled).then(move |_| {
+            watch.end(peer);
+            Ok(())
+        }))
+    }
+}
+
+/// Serves SMTP on an already bound listener, either bound at startup or passed in by systemd
+///
+/// # Parameters
+/// * `listener` - the bound listening socket
+/// * `service` - the TCP service handling each connection
+pub fn serve_listener<S, Fut>(
+    listener: std::net::TcpListener,
+    service: S,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: TcpService<Future = Fut> + Clone + Send + 'static,
+    Fut: Future<Item = (), Error = ()> + Send + 'static,
+{
+    future::lazy(move || {
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener, &Handle::default())
+    })
+    .map_err(|e| error!("Failed to use listener: {}", e))
+    .and_then(move |listener| {
+        if let Ok(local) = listener.local_addr() {
+            info!("listening on {}", local);
+        }
+        listener
+            .incoming()
+            .for_each(move |tcp| {
+                tokio::spawn(service.clone().handle(tcp));
+                Ok(())
+            })
+            .map_err(|e| error!("Stopped accepting connections: {}", e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a mailbox path
+    ///
+    /// # Parameters
+    /// * `local` - the local part
+    /// * `domain` - the domain
+    fn path(local: &str, domain: &str) -> SmtpPath {
+        SmtpPath::Direct(SmtpAddress::Mailbox(
+            local.into(),
+            SmtpHost::Domain(domain.into()),
+        ))
+    }
+
+    #[test]
+    fn served_domains_accept_their_recipients() {
+        let served = ServedDomains::new(vec!["example.com".into(), "*.example.net".into()]);
+        assert!(served.accepts(&path("alerts", "example.com")));
+        assert!(served.accepts(&path("alerts", "EXAMPLE.com.")));
+        assert!(served.accepts(&path("alerts", "mail.example.net")));
+        assert!(served.accepts(&SmtpPath::Postmaster));
+    }
+
+    #[test]
+    fn unserved_domains_are_refused() {
+        let served = ServedDomains::new(vec!["example.com".into(), "*.example.net".into()]);
+        assert!(!served.accepts(&path("alerts", "example.org")));
+        assert!(!served.accepts(&path("alerts", "mail.example.com")));
+        // A wildcard only covers subdomains
+        assert!(!served.accepts(&path("alerts", "example.net")));
+        assert!(!served.accepts(&path("alerts", "badexample.net")));
+    }
+
+    #[test]
+    fn no_served_domains_accept_everything() {
+        let served = ServedDomains::default();
+        assert!(served.accepts(&path("alerts", "example.org")));
+    }
+
+    #[test]
+    fn slow_data_feed_times_out() {
+        let mut monitor = DataRateMonitor::new(1000, Duration::from_millis(50));
+        monitor.record(10).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        let err = monitor.record(10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn fast_data_feed_passes() {
+        let mut monitor = DataRateMonitor::new(1000, Duration::from_millis(50));
+        monitor.record(10).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(monitor.record(100_000).is_ok());
+    }
+
+    #[test]
+    fn data_idle_watch_tracks_stalled_transfers() {
+        let watch = DataIdleWatch::new();
+        let peer: SocketAddr = "127.0.0.1:2525".parse().unwrap();
+        assert!(watch.idle(peer).is_none());
+        watch.record(peer);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(watch.idle(peer).unwrap() >= Duration::from_millis(20));
+        watch.record(peer);
+        assert!(watch.idle(peer).unwrap() < Duration::from_millis(20));
+        watch.end(peer);
+        assert!(watch.idle(peer).is_none());
+    }
 }
@@ -0,0 +1,83 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::headers::Headers;
+use serde::Deserialize;
+
+/// What happens to mail an upstream filter marked as spam
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamAction {
+    /// The mail is refused with a permanent failure
+    #[default]
+    Reject,
+    /// The mail is accepted but not posted
+    Drop,
+}
+
+/// Checks the spam headers added by an upstream filter such as SpamAssassin
+///
+/// Mail without the headers is never treated as spam.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SpamCheck {
+    /// Whether `X-Spam-Flag: YES` marks mail as spam
+    #[serde(default = "default_honor_flag")]
+    pub honor_flag: bool,
+    /// Score in `X-Spam-Status` at or above which mail is spam
+    pub threshold: Option<f64>,
+    /// What happens to spam
+    #[serde(default)]
+    pub action: SpamAction,
+}
+
+fn default_honor_flag() -> bool {
+    true
+}
+
+impl SpamCheck {
+    /// Checks whether the headers mark a message as spam
+    ///
+    /// # Parameters
+    /// * `headers` - the message's headers
+    pub fn is_spam(&self, headers: &Headers) -> bool {
+        let flagged = self.honor_flag
+            && headers
+                .get("X-Spam-Flag")
+                .is_some_and(|flag| flag.trim().eq_ignore_ascii_case("yes"));
+        let over_threshold = match (self.threshold, headers.get("X-Spam-Status")) {
+            (Some(threshold), Some(status)) => spam_score(status).is_some_and(|s| s >= threshold),
+            _ => false,
+        };
+        flagged || over_threshold
+    }
+}
+
+/// Pulls the score out of an `X-Spam-Status` value such as `Yes, score=7.3 required=5.0`
+///
+/// Older SpamAssassin versions call the score `hits`.
+///
+/// # Parameters
+/// * `status` - the header value
+fn spam_score(status: &str) -> Option<f64> {
+    status
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .find_map(|token| {
+            token
+                .strip_prefix("score=")
+                .or_else(|| token.strip_prefix("hits="))
+        })
+        .and_then(|score| score.parse().ok())
+}
@@ -0,0 +1,136 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::process;
+
+/// First file descriptor systemd uses for passed sockets
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening sockets passed in by systemd socket activation
+///
+/// Returns an empty list when the process was not socket activated. The activation variables
+/// are removed from the environment so child processes don't try to use the sockets too.
+#[cfg(unix)]
+pub fn listeners() -> Vec<TcpListener> {
+    // The sockets are only meant for us if LISTEN_PID is our pid
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == process::id() && fds > 0 => {
+            (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+                // systemd hands ownership of these descriptors to us
+                .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Takes the listening sockets passed in by systemd socket activation
+///
+/// systemd only runs on Unix, so there are never any sockets to take elsewhere.
+#[cfg(not(unix))]
+pub fn listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+/// Tells systemd the service has finished starting up
+///
+/// Does nothing when not running under a systemd notify service.
+pub fn notify_ready() -> io::Result<()> {
+    notify("READY=1")
+}
+
+/// Sends a state change to the systemd notify socket, if there is one
+///
+/// # Parameters
+/// * `state` - newline separated state assignments
+#[cfg(unix)]
+fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    // A leading @ means the socket lives in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        send_abstract(&socket, name, state)?;
+    } else {
+        socket.send_to(state.as_bytes(), &*path)?;
+    }
+    Ok(())
+}
+
+/// Sends a state change to the systemd notify socket
+///
+/// There is no notify socket off Unix, so this does nothing.
+///
+/// # Parameters
+/// * `state` - newline separated state assignments
+#[cfg(not(unix))]
+fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sends a datagram to a socket in the abstract namespace
+///
+/// # Parameters
+/// * `socket` - the unbound socket to send from
+/// * `name` - the abstract socket name, without the leading @
+/// * `state` - newline separated state assignments
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Sends a datagram to a socket in the abstract namespace
+///
+/// Only Linux has an abstract namespace, so this always fails elsewhere.
+///
+/// # Parameters
+/// * `socket` - the unbound socket to send from
+/// * `name` - the abstract socket name, without the leading @
+/// * `state` - newline separated state assignments
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send_abstract(_socket: &UnixDatagram, name: &str, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "abstract notify socket @{} is only supported on Linux",
+            name
+        ),
+    ))
+}
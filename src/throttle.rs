@@ -0,0 +1,149 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use log::error;
+use std::time::{Duration, Instant};
+
+/// Default number of repeats of an error after which it is logged again
+pub const DEFAULT_ERROR_LOG_EVERY: u64 = 100;
+/// Default time after which a repeated error is logged again
+pub const DEFAULT_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Error logger that collapses runs of identical messages
+///
+/// The first occurrence of a message is always logged. Repeats are counted and only logged
+/// once every `every` occurrences or once every `interval`, whichever comes first.
+pub struct ThrottledLogger {
+    /// Number of repeats after which a message is logged again
+    every: u64,
+    /// Time after which a repeated message is logged again
+    interval: Duration,
+    /// Last message seen
+    last_message: Option<String>,
+    /// Number of repeats of the last message that have not been logged
+    suppressed: u64,
+    /// When a message was last logged
+    last_logged: Instant,
+}
+
+impl ThrottledLogger {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `every` - number of repeats after which a message is logged again
+    /// * `interval` - time after which a repeated message is logged again
+    pub fn new(every: u64, interval: Duration) -> Self {
+        Self {
+            every,
+            interval,
+            last_message: None,
+            suppressed: 0,
+            last_logged: Instant::now(),
+        }
+    }
+
+    /// Logs an error unless it is a recent repeat
+    ///
+    /// # Parameters
+    /// * `message` - the error message
+    pub fn error(&mut self, message: &str) {
+        let now = Instant::now();
+        if self.last_message.as_deref() == Some(message) {
+            self.suppressed += 1;
+            if self.suppressed < self.every && now.duration_since(self.last_logged) < self.interval
+            {
+                return;
+            }
+            error!("{} (repeated {} times)", message, self.suppressed);
+        } else {
+            // Report how often the previous message went unlogged before moving on
+            if let Some(last_message) = &self.last_message {
+                if self.suppressed > 0 {
+                    error!("{} (repeated {} times)", last_message, self.suppressed);
+                }
+            }
+            error!("{}", message);
+            self.last_message = Some(message.into());
+        }
+        self.suppressed = 0;
+        self.last_logged = now;
+    }
+}
+
+impl Default for ThrottledLogger {
+    fn default() -> Self {
+        Self::new(DEFAULT_ERROR_LOG_EVERY, DEFAULT_ERROR_LOG_INTERVAL)
+    }
+}
+
+/// Caps how many messages are posted in each fixed time window
+///
+/// Messages over the cap are dropped. The number dropped is handed back once its window has
+/// ended, so it can be reported.
+#[derive(Clone, Copy, Debug)]
+pub struct PostLimiter {
+    /// Number of messages allowed in a window
+    max_posts: u64,
+    /// Length of each window
+    window: Duration,
+    /// When the current window started
+    window_start: Instant,
+    /// Messages posted in the current window
+    posted: u64,
+    /// Messages dropped in the current window
+    suppressed: u64,
+}
+
+impl PostLimiter {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `max_posts` - number of messages allowed in a window
+    /// * `window` - length of each window
+    pub fn new(max_posts: u64, window: Duration) -> Self {
+        Self {
+            max_posts,
+            window,
+            window_start: Instant::now(),
+            posted: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Counts a message against the cap
+    ///
+    /// Returns whether the message may be posted, and the number of messages dropped in the
+    /// previous window if that window has just ended.
+    pub fn check(&mut self) -> (bool, Option<u64>) {
+        let now = Instant::now();
+        let mut ended_suppressed = None;
+        if now.duration_since(self.window_start) >= self.window {
+            if self.suppressed > 0 {
+                ended_suppressed = Some(self.suppressed);
+            }
+            self.window_start = now;
+            self.posted = 0;
+            self.suppressed = 0;
+        }
+        if self.posted < self.max_posts {
+            self.posted += 1;
+            (true, ended_suppressed)
+        } else {
+            self.suppressed += 1;
+            (false, ended_suppressed)
+        }
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2020 Jade
+// This file is part of smtp_discord_bridge.
+//
+// smtp_discord_bridge is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// smtp_discord_bridge is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with smtp_discord_bridge.  If not, see <https://www.gnu.org/licenses/>.
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::{self, Write};
+
+/// Signature starting each file's local header
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+/// Signature starting each file's central directory entry
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+/// Signature starting the end of central directory record
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+/// Zip version needed to extract deflated files
+const VERSION_NEEDED: u16 = 20;
+/// General purpose flag marking file names as UTF-8
+const FLAG_UTF8_NAMES: u16 = 0x0800;
+/// Compression method for deflated files
+const METHOD_DEFLATE: u16 = 8;
+/// MS-DOS date of 1980-01-01, the earliest a zip file can carry
+const DOS_EPOCH_DATE: u16 = 0x0021;
+
+/// Bundles files into a zip archive in memory, each deflated
+///
+/// # Parameters
+/// * `files` - the name and contents of each file
+pub fn archive(files: &[(&str, &[u8])]) -> io::Result<Vec<u8>> {
+    let mut zip = Vec::new();
+    let mut central_directory = Vec::new();
+    for (name, data) in files {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let offset = zip.len();
+        // Everything about the file but where it starts is the same in both headers
+        let mut entry = Vec::new();
+        put_u16(&mut entry, VERSION_NEEDED);
+        put_u16(&mut entry, FLAG_UTF8_NAMES);
+        put_u16(&mut entry, METHOD_DEFLATE);
+        put_u16(&mut entry, 0);
+        put_u16(&mut entry, DOS_EPOCH_DATE);
+        put_u32(&mut entry, crc.sum());
+        put_u32(&mut entry, compressed.len() as u32);
+        put_u32(&mut entry, data.len() as u32);
+        put_u16(&mut entry, name.len() as u16);
+        put_u16(&mut entry, 0);
+
+        put_u32(&mut zip, LOCAL_HEADER_SIGNATURE);
+        zip.extend_from_slice(&entry);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(&compressed);
+
+        put_u32(&mut central_directory, CENTRAL_HEADER_SIGNATURE);
+        put_u16(&mut central_directory, VERSION_NEEDED);
+        central_directory.extend_from_slice(&entry);
+        // No comment, disk number, or file attributes
+        put_u16(&mut central_directory, 0);
+        put_u16(&mut central_directory, 0);
+        put_u16(&mut central_directory, 0);
+        put_u32(&mut central_directory, 0);
+        put_u32(&mut central_directory, offset as u32);
+        central_directory.extend_from_slice(name.as_bytes());
+    }
+    let central_directory_offset = zip.len();
+    zip.extend_from_slice(&central_directory);
+    put_u32(&mut zip, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    // Everything is on the one disk
+    put_u16(&mut zip, 0);
+    put_u16(&mut zip, 0);
+    put_u16(&mut zip, files.len() as u16);
+    put_u16(&mut zip, files.len() as u16);
+    put_u32(&mut zip, central_directory.len() as u32);
+    put_u32(&mut zip, central_directory_offset as u32);
+    put_u16(&mut zip, 0);
+    Ok(zip)
+}
+
+/// Appends a little-endian 16-bit value
+///
+/// # Parameters
+/// * `buf` - the buffer to append to
+/// * `value` - the value
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Appends a little-endian 32-bit value
+///
+/// # Parameters
+/// * `buf` - the buffer to append to
+/// * `value` - the value
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::DeflateDecoder;
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    /// Reads a little-endian 16-bit value
+    ///
+    /// # Parameters
+    /// * `buf` - the buffer to read from
+    /// * `at` - where the value starts
+    fn u16_at(buf: &[u8], at: usize) -> usize {
+        u16::from_le_bytes(buf[at..at + 2].try_into().expect("two bytes")) as usize
+    }
+
+    /// Reads a little-endian 32-bit value
+    ///
+    /// # Parameters
+    /// * `buf` - the buffer to read from
+    /// * `at` - where the value starts
+    fn u32_at(buf: &[u8], at: usize) -> usize {
+        u32::from_le_bytes(buf[at..at + 4].try_into().expect("four bytes")) as usize
+    }
+
+    #[test]
+    fn files_read_back_through_the_central_directory() {
+        let body = "line\n".repeat(1000);
+        let files: &[(&str, &[u8])] = &[("message.eml", body.as_bytes()), ("b.txt", b"short")];
+        let zip = archive(files).expect("archive");
+        let end = zip.len() - 22;
+        assert_eq!(
+            u32_at(&zip, end),
+            END_OF_CENTRAL_DIRECTORY_SIGNATURE as usize
+        );
+        assert_eq!(u16_at(&zip, end + 10), 2);
+        let mut central = u32_at(&zip, end + 16);
+        for (name, data) in files {
+            assert_eq!(u32_at(&zip, central), CENTRAL_HEADER_SIGNATURE as usize);
+            let name_len = u16_at(&zip, central + 28);
+            assert_eq!(&zip[central + 46..central + 46 + name_len], name.as_bytes());
+            let local = u32_at(&zip, central + 42);
+            assert_eq!(u32_at(&zip, local), LOCAL_HEADER_SIGNATURE as usize);
+            let compressed_len = u32_at(&zip, local + 18);
+            let start = local + 30 + u16_at(&zip, local + 26);
+            let mut unzipped = Vec::new();
+            DeflateDecoder::new(&zip[start..start + compressed_len])
+                .read_to_end(&mut unzipped)
+                .expect("inflate");
+            assert_eq!(&unzipped, data);
+            let mut crc = Crc::new();
+            crc.update(&unzipped);
+            assert_eq!(u32_at(&zip, local + 14), crc.sum() as usize);
+            central += 46 + name_len;
+        }
+        // Repetitive mail shrinks a good deal
+        assert!(zip.len() < body.len() / 10);
+    }
+}